@@ -0,0 +1,19 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use payment_engine::csv_handler;
+
+// Deserializing arbitrary input may fail, but it must never panic, and skipping the malformed
+// rows must always reach the end of the input.
+fuzz_target!(|data: &[u8]| {
+    let mut bytes = data;
+    for row in csv_handler::stream_transactions(&mut bytes, false) {
+        let _ = row;
+    }
+
+    let mut bytes = data;
+    let mut malformed = Vec::new();
+    let rows = csv_handler::stream_transactions(&mut bytes, false);
+    for row in csv_handler::skip_malformed(rows, &mut malformed) {
+        let _ = row;
+    }
+});
@@ -0,0 +1,28 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use payment_engine::csv_handler;
+use payment_engine::PaymentEngine;
+
+// Full run of the CLI pipeline: parse, process in batch and in stream mode, write the accounts.
+fuzz_target!(|data: &[u8]| {
+    let mut bytes = data;
+    let rows = match csv_handler::read_transactions(&mut bytes, false) {
+        Ok(rows) => rows,
+        Err(_) => return,
+    };
+
+    let mut batch = PaymentEngine::default();
+    let batch_rejections = batch.apply_batch(rows);
+
+    let mut bytes = data;
+    let mut stream = PaymentEngine::default();
+    let stream_rejections = stream
+        .apply_stream(csv_handler::stream_transactions(&mut bytes, false))
+        .expect("input was parsed once already");
+
+    assert_eq!(batch.snapshot(), stream.snapshot());
+    assert_eq!(batch_rejections, stream_rejections);
+
+    let mut out = Vec::new();
+    csv_handler::write_accounts(&batch.snapshot(), &mut out).expect("writing to memory failed");
+});
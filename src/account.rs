@@ -1,17 +1,109 @@
 use crate::transaction::*;
 
-use itertools::Itertools;
-use rayon::prelude::*;
+use chrono::Duration;
 use rust_decimal::{Decimal, RoundingStrategy};
-use serde::Serialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::ops::Neg;
+use std::str::FromStr;
 
-#[derive(Debug, PartialEq)]
+/// How disputes of Withdrawals are handled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WithdrawalDisputes {
+    /// Withdrawals cannot be disputed.
+    Forbid,
+    /// Works like a Deposit dispute with negated amount: the withdrawn amount is credited to available during the dispute.
+    Symmetric,
+    /// Nothing is held or credited during the dispute, the withdrawn amount is only credited on chargeback.
+    HoldWithoutCredit,
+}
+
+impl WithdrawalDisputes {
+    pub const VALUES: [&'static str; 3] = ["forbid", "symmetric", "hold-without-credit"];
+}
+
+impl FromStr for WithdrawalDisputes {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "forbid" => Ok(WithdrawalDisputes::Forbid),
+            "symmetric" => Ok(WithdrawalDisputes::Symmetric),
+            "hold-without-credit" => Ok(WithdrawalDisputes::HoldWithoutCredit),
+            _ => Err(format!("unknown withdrawal dispute policy: {}", s)),
+        }
+    }
+}
+
+/// Engine-wide rules of the dispute lifecycle.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DisputeRules {
+    pub allow_redispute: bool, // whether a resolved transaction can be disputed again
+    pub withdrawals: WithdrawalDisputes,
+    pub window: Option<Duration>, // how long after its timestamp a transaction can be disputed
+    pub prune_expired: bool,      // whether transactions past the window are forgotten
+}
+
+impl Default for DisputeRules {
+    fn default() -> Self {
+        DisputeRules {
+            allow_redispute: true,
+            withdrawals: WithdrawalDisputes::Symmetric,
+            window: None,
+            prune_expired: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DisputeAction {
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DisputeState {
+    Settled,
+    Disputed,
+    Resolved,
+    ChargedBack,
+    Reversed,
+}
+
+impl DisputeState {
+    /// Transition table of the dispute lifecycle.
+    pub fn next(self, action: DisputeAction, rules: &DisputeRules) -> Result<DisputeState> {
+        use DisputeAction::*;
+        use DisputeState::*;
+
+        match (self, action) {
+            (Settled, Dispute) => Ok(Disputed),
+            (Resolved, Dispute) if rules.allow_redispute => Ok(Disputed),
+            (Resolved, Dispute) => Err(Error::AlreadyResolved),
+            (Disputed, Dispute) => Err(Error::AlreadyDisputed),
+            (Disputed, Resolve) => Ok(Resolved),
+            (Disputed, Chargeback) => Ok(ChargedBack),
+            (Settled, _) | (Resolved, _) => Err(Error::NotDisputed),
+            (ChargedBack, _) => Err(Error::AlreadyChargedBack),
+            (Reversed, _) => Err(Error::AlreadyReversed),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct TransactionStatus {
+    pub transaction_type: TransactionType,
+    pub currency: Currency,
     pub amount_change: Decimal,
-    pub disputed: bool,
-    pub chargeback: bool,
+    pub state: DisputeState,
+    #[serde(default)]
+    pub disputed: Decimal, // part of amount_change under dispute, with the same sign
+    #[serde(default)]
+    pub refunded: Decimal, // part of amount_change already refunded, with the same sign
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
 }
 
 impl TransactionStatus {
@@ -24,89 +116,392 @@ impl TransactionStatus {
         }
 
         Ok(TransactionStatus {
+            transaction_type: transaction.transaction_type.clone(),
+            currency: transaction.currency().to_string(),
             amount_change,
-            disputed: false,
-            chargeback: false,
+            state: DisputeState::Settled,
+            disputed: Decimal::ZERO,
+            refunded: Decimal::ZERO,
+            timestamp: transaction.timestamp,
         })
     }
 
-    pub fn dispute(&mut self) -> Result<Decimal> {
-        if self.disputed {
-            return Err(Error::AlreadyDisputed);
+    /// Part of the disputed portion which is moved between available and held by a dispute.
+    pub fn held_amount(&self, portion: Decimal, rules: &DisputeRules) -> Result<Decimal> {
+        if self.transaction_type != TransactionType::Withdrawal {
+            return Ok(portion);
+        }
+        match rules.withdrawals {
+            WithdrawalDisputes::Forbid => Err(Error::WithdrawalDisputeForbidden),
+            WithdrawalDisputes::Symmetric => Ok(portion),
+            WithdrawalDisputes::HoldWithoutCredit => Ok(Decimal::ZERO),
+        }
+    }
+
+    /// Moves to the next dispute state, returning the portion of the amount affected by the action.
+    /// A dispute covers the given amount, or the whole transaction without one; while the transaction
+    /// is disputed, further disputes with an amount add to the disputed portion.
+    /// Resolves and chargebacks settle the whole disputed portion.
+    pub fn apply(&mut self, action: DisputeAction, amount: Option<Decimal>, rules: &DisputeRules) -> Result<Decimal> {
+        let adding = action == DisputeAction::Dispute && self.state == DisputeState::Disputed && amount.is_some();
+        if !adding {
+            self.state = self.state.next(action, rules)?;
+        }
+        if action != DisputeAction::Dispute {
+            return Ok(std::mem::take(&mut self.disputed));
+        }
+
+        let portion = self.portion(amount);
+        match self.disputed.checked_add(portion) {
+            Some(disputed) if !portion.is_zero() && disputed.abs() <= self.remaining().abs() => self.disputed = disputed,
+            _ => return Err(Error::DisputedAmountExceeded),
+        }
+        Ok(portion)
+    }
+
+    /// Part of amount_change which was not refunded.
+    pub fn remaining(&self) -> Decimal {
+        self.amount_change - self.refunded
+    }
+
+    /// The given amount with the sign of amount_change, or the remaining amount without one.
+    fn portion(&self, amount: Option<Decimal>) -> Decimal {
+        match amount {
+            Some(amount) if self.amount_change.is_sign_negative() => amount.neg(),
+            Some(amount) => amount,
+            None => self.remaining(),
+        }
+    }
+
+    /// Checks whether the transaction can be refunded or reversed in its current state.
+    fn check_returnable(&self) -> Result<()> {
+        match self.state {
+            DisputeState::Settled | DisputeState::Resolved => Ok(()),
+            DisputeState::Disputed => Err(Error::AlreadyDisputed),
+            DisputeState::ChargedBack => Err(Error::AlreadyChargedBack),
+            DisputeState::Reversed => Err(Error::AlreadyReversed),
         }
-        self.disputed = true;
-        Ok(self.amount_change)
     }
 
-    pub fn resolve(&mut self) -> Result<Decimal> {
-        if !self.disputed {
-            return Err(Error::NotDisputed);
+    /// Refunds the given amount, or the rest of the transaction without one, returning the refunded portion.
+    pub fn refund(&mut self, amount: Option<Decimal>) -> Result<Decimal> {
+        self.check_returnable()?;
+        let portion = self.portion(amount);
+        match self.refunded.checked_add(portion) {
+            Some(refunded) if !portion.is_zero() && refunded.abs() <= self.amount_change.abs() => self.refunded = refunded,
+            _ => return Err(Error::RefundedAmountExceeded),
         }
-        self.disputed = false;
-        Ok(self.amount_change)
+        Ok(portion)
     }
 
-    pub fn chargeback(&mut self) -> Result<Decimal> {
-        if !self.disputed {
-            return Err(Error::NotDisputed);
+    /// Cancels the rest of the transaction, returning the reversed portion.
+    pub fn reverse(&mut self) -> Result<Decimal> {
+        self.check_returnable()?;
+        self.state = DisputeState::Reversed;
+        Ok(self.remaining())
+    }
+}
+
+/// Funds of an account in one currency.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Balance {
+    pub available: Decimal,
+    pub held: Decimal,
+}
+
+impl Balance {
+    pub fn total(&self) -> Decimal {
+        self.available + self.held
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.available.is_zero() && self.held.is_zero()
+    }
+
+    /// Changes the balance only if available, held and their total all stay within the range of `Decimal`.
+    fn update(&mut self, available_change: Decimal, held_change: Decimal) -> Result<()> {
+        // adding a negative zero would turn a zero balance into "-0"
+        let add = |balance: Decimal, change: Decimal| {
+            if change.is_zero() {
+                Some(balance)
+            } else {
+                balance.checked_add(change)
+            }
+        };
+        let available = add(self.available, available_change);
+        let held = add(self.held, held_change);
+        match (available, held) {
+            (Some(available), Some(held)) if available.checked_add(held).is_some() => {
+                self.available = available;
+                self.held = held;
+                Ok(())
+            }
+            _ => Err(Error::BalanceOverflow),
         }
-        self.chargeback = true;
-        Ok(self.amount_change)
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Account {
     client_id: ClientId,
-    available: Decimal,
-    held: Decimal,
+    balances: BTreeMap<Currency, Balance>,
     locked: bool,
+    #[serde(default)]
+    closed: bool,
     transaction_status: HashMap<TransactionId, TransactionStatus>, // Deposits and Withdrawals only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_timestamp: Option<Timestamp>, // latest timestamp of the accepted transactions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pruned_before: Option<Timestamp>, // transactions older than this may have been forgotten
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    fees_paid: BTreeMap<Currency, Decimal>,
+    #[serde(skip)]
+    credit_limits: BTreeMap<Currency, Decimal>, // set from the config of the run, not saved
 }
 
 impl Account {
     pub fn new(client_id: ClientId) -> Account {
         Account {
             client_id,
-            available: Decimal::ZERO,
-            held: Decimal::ZERO,
+            balances: BTreeMap::new(),
             locked: false,
+            closed: false,
             transaction_status: HashMap::new(),
+            last_timestamp: None,
+            pruned_before: None,
+            fees_paid: BTreeMap::new(),
+            credit_limits: BTreeMap::new(),
         }
     }
 
-    pub fn from_transactions(client_id: &ClientId, transactions: &[Transaction], verbose: bool) -> Account {
-        let mut acc = Account::new(client_id.to_owned());
+    pub fn client_id(&self) -> ClientId {
+        self.client_id
+    }
 
-        for tr in transactions {
-            if let Err(e) = acc.process(tr, verbose) {
-                if verbose {
-                    println!("Ignoring transaction with ID: {}. Reason: {:?}", tr.transaction_id, e)
-                }
-            }
-        }
+    /// Balance in the given currency, zero if the account never used it.
+    pub fn balance(&self, currency: &str) -> Balance {
+        self.balances.get(currency).copied().unwrap_or_default()
+    }
 
-        acc
+    /// Balances of the used currencies, ordered by currency.
+    pub fn balances(&self) -> impl Iterator<Item = (&Currency, &Balance)> {
+        self.balances.iter()
+    }
+
+    /// Available funds in the default currency.
+    pub fn available(&self) -> Decimal {
+        self.balance(DEFAULT_CURRENCY).available
+    }
+
+    /// Held funds in the default currency.
+    pub fn held(&self) -> Decimal {
+        self.balance(DEFAULT_CURRENCY).held
     }
 
+    /// Total funds in the default currency.
     pub fn total(&self) -> Decimal {
-        self.available + self.held
+        self.balance(DEFAULT_CURRENCY).total()
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn last_timestamp(&self) -> Option<Timestamp> {
+        self.last_timestamp
+    }
+
+    /// Whether transactions past the dispute window may have been forgotten.
+    pub fn has_pruned(&self) -> bool {
+        self.pruned_before.is_some()
+    }
+
+    /// Fails if the transaction is older than an already accepted one. Transactions without timestamp always pass.
+    pub fn check_order(&self, tr: &Transaction) -> Result<()> {
+        match (self.last_timestamp, tr.timestamp) {
+            (Some(last), Some(timestamp)) if timestamp < last => Err(Error::OutOfOrder),
+            _ => Ok(()),
+        }
+    }
+
+    fn update_balance(&mut self, currency: &str, available_change: Decimal, held_change: Decimal) -> Result<()> {
+        let mut balance = self.balance(currency);
+        balance.update(available_change, held_change)?;
+        self.balances.insert(currency.to_string(), balance);
+        Ok(())
+    }
+
+    /// Sum of the fees charged in the currency.
+    pub fn fees_paid(&self, currency: &str) -> Decimal {
+        self.fees_paid.get(currency).copied().unwrap_or_default()
+    }
+
+    /// Replaces the credit limits of the account, withdrawals may take the available funds down to `-limit`.
+    pub fn set_credit_limits(&mut self, limits: BTreeMap<Currency, Decimal>) {
+        self.credit_limits = limits;
+    }
+
+    /// Credit limit in the currency, zero if the client has no credit line in it.
+    pub fn credit_limit(&self, currency: &str) -> Decimal {
+        self.credit_limits.get(currency).copied().unwrap_or_default()
+    }
+
+    /// Part of the credit line in use: the negative available funds, up to the limit.
+    pub fn credit_used(&self, currency: &str) -> Decimal {
+        let available = self.balance(currency).available;
+        if available < Decimal::ZERO {
+            available.neg().min(self.credit_limit(currency))
+        } else {
+            Decimal::ZERO
+        }
+    }
+
+    /// Amount a percentage fee is based on: the disputed part for Resolves and Chargebacks, otherwise the amount
+    /// of the row, or the remaining part of the referred transaction for rows without amount.
+    pub fn fee_base(&self, tr: &Transaction) -> Decimal {
+        let status = self.transaction_status.get(&tr.transaction_id);
+        if matches!(tr.transaction_type, TransactionType::Resolve | TransactionType::Chargeback) {
+            return status.map_or(Decimal::ZERO, |status| status.disputed.abs());
+        }
+        match (tr.amount, status) {
+            (Some(amount), _) => amount.abs(),
+            (None, Some(status)) if !tr.transaction_type.creates_transaction_id() => status.remaining().abs(),
+            _ => Decimal::ZERO,
+        }
+    }
+
+    /// Processes the transaction and charges its fee from the available funds, changing nothing if either fails.
+    /// The fee must be covered by the available funds and the credit line (a deposit may pay it from the deposited
+    /// amount). It is taken first, so the transactions which need funds need them for the fee too.
+    pub fn process_charged(&mut self, tr: &Transaction, rules: &DisputeRules, fee: Decimal, verbose: bool) -> Result<()> {
+        if fee.is_zero() {
+            return self.process_with(tr, rules, verbose);
+        }
+        let currency = self.transaction_currency(tr).to_string();
+        let paid = self.fees_paid(&currency).checked_add(fee).ok_or(Error::BalanceOverflow)?;
+        let before = self.balances.get(&currency).copied();
+
+        let incoming = match tr.transaction_type {
+            TransactionType::Deposit => tr.amount.unwrap_or_default(),
+            _ => Decimal::ZERO,
+        };
+        let limit = self.credit_limit(&currency);
+        let funds = self.balance(&currency).available.checked_add(limit).and_then(|funds| funds.checked_add(incoming));
+        if funds.is_some_and(|funds| funds < fee) {
+            return Err(if limit.is_zero() {
+                Error::InsufficientFunds
+            } else {
+                Error::CreditLimitExceeded
+            });
+        }
+
+        self.update_balance(&currency, fee.neg(), Decimal::ZERO)?;
+        if let Err(e) = self.process_with(tr, rules, verbose) {
+            match before {
+                Some(balance) => self.balances.insert(currency, balance),
+                None => self.balances.remove(&currency),
+            };
+            return Err(e);
+        }
+        self.fees_paid.insert(currency, paid);
+        Ok(())
+    }
+
+    /// Checks that the account can receive the transfer, returning its balance after the transfer.
+    /// Nothing is changed, so the transfer can still fail on the side of the sender.
+    pub fn prepare_receive(&self, tr: &Transaction) -> Result<Balance> {
+        if self.closed {
+            return Err(Error::AccountClosed);
+        }
+        if self.locked {
+            return Err(Error::AccountLocked);
+        }
+        let mut balance = self.balance(tr.currency());
+        balance.update(tr.get_amount()?, Decimal::ZERO)?;
+        Ok(balance)
     }
 
-    fn get_transaction_status(&mut self, tr_id: TransactionId) -> Result<&mut TransactionStatus> {
+    /// Stores the balance returned by `prepare_receive`, once the sender side of the transfer is done.
+    /// Transfers are not stored in the transaction history, so they cannot be disputed.
+    pub fn commit_receive(&mut self, tr: &Transaction, balance: Balance) {
+        self.balances.insert(tr.currency().to_string(), balance);
+    }
+
+    /// Currency whose balance is changed by the transaction: disputes use the currency of the disputed transaction.
+    pub fn transaction_currency<'a>(&'a self, tr: &'a Transaction) -> &'a str {
+        if tr.transaction_type.creates_transaction_id() {
+            return tr.currency();
+        }
         self.transaction_status
-            .get_mut(&tr_id)
-            .ok_or(Error::UnknownTransactionId)
+            .get(&tr.transaction_id)
+            .map_or(tr.currency(), |status| status.currency.as_str())
+    }
+
+    /// Status of the disputed transaction, a currency given in the dispute must match its currency.
+    fn get_transaction_status(&self, tr: &Transaction) -> Result<&TransactionStatus> {
+        let status = self
+            .transaction_status
+            .get(&tr.transaction_id)
+            .ok_or(Error::UnknownTransactionId)?;
+        match &tr.currency {
+            Some(currency) if *currency != status.currency => Err(Error::CurrencyMismatch),
+            _ => Ok(status),
+        }
+    }
+
+    /// Disputes are checked against the later of their own timestamp and the latest one of the account, so a backdated
+    /// dispute cannot reach a transaction which may already be pruned. Transactions without timestamp can always be disputed.
+    fn check_dispute_window(&self, status: &TransactionStatus, tr: &Transaction, rules: &DisputeRules) -> Result<()> {
+        let now = tr.timestamp.max(self.last_timestamp);
+        match (rules.window, status.timestamp, now) {
+            (Some(window), Some(timestamp), Some(now)) if now - timestamp > window => Err(Error::DisputeWindowExpired),
+            _ => Ok(()),
+        }
+    }
+
+    /// Forgets the transactions which can no longer be disputed, keeping the ones under dispute.
+    /// Runs at most once per window length, so the cost is spread over the transactions of the window.
+    /// Their IDs are still known to the registry of the engine, which rejects them when reused.
+    fn prune_expired(&mut self, rules: &DisputeRules) {
+        let (window, now) = match (rules.window, self.last_timestamp) {
+            (Some(window), Some(now)) if rules.prune_expired => (window, now),
+            _ => return,
+        };
+        // nothing can have expired if the window reaches back before the earliest representable time
+        let cutoff = match now.checked_sub_signed(window) {
+            Some(cutoff) => cutoff,
+            None => return,
+        };
+        if self.pruned_before.is_some_and(|pruned| cutoff - pruned < window) {
+            return;
+        }
+        self.transaction_status.retain(|_, status| {
+            status.state == DisputeState::Disputed || status.timestamp.is_none_or(|t| t >= cutoff)
+        });
+        self.pruned_before = Some(cutoff);
     }
 
     pub fn process(&mut self, tr: &Transaction, verbose: bool) -> Result<()> {
+        self.process_with(tr, &DisputeRules::default(), verbose)
+    }
+
+    pub fn process_with(&mut self, tr: &Transaction, rules: &DisputeRules, verbose: bool) -> Result<()> {
         use TransactionType::*;
 
         if self.client_id != tr.client_id {
             return Err(Error::ClientIdMismatch);
         }
 
-        if self.locked {
+        if self.closed {
+            return Err(Error::AccountClosed);
+        }
+
+        // administrative operations are allowed on locked accounts
+        if self.locked && !tr.transaction_type.is_admin() {
             return Err(Error::AccountLocked);
         }
 
@@ -117,7 +512,7 @@ impl Account {
                 }
                 let status = TransactionStatus::new(tr)?;
 
-                self.available += status.amount_change;
+                self.update_balance(&status.currency, status.amount_change, Decimal::ZERO)?;
                 self.transaction_status.insert(tr.transaction_id, status);
             }
             Withdrawal => {
@@ -125,99 +520,234 @@ impl Account {
                     return Err(Error::DuplicatedTransactionId);
                 }
                 let status = TransactionStatus::new(tr)?;
-                if (self.available + status.amount_change).is_sign_negative() {
-                    return Err(Error::InsufficientFunds);
+                let limit = self.credit_limit(&status.currency);
+                let spendable = self.balance(&status.currency).available.checked_add(limit);
+                if spendable.is_some_and(|funds| funds < status.amount_change.neg()) {
+                    return Err(if limit.is_zero() {
+                        Error::InsufficientFunds
+                    } else {
+                        Error::CreditLimitExceeded
+                    });
                 }
 
-                self.available += status.amount_change;
+                self.update_balance(&status.currency, status.amount_change, Decimal::ZERO)?;
                 self.transaction_status.insert(tr.transaction_id, status);
             }
+            // the dispute state is only stored once the balances are updated
             Dispute => {
-                tr.check_amount_empty(verbose);
-                let amount_change = {
-                    let ref_tr = self.get_transaction_status(tr.transaction_id)?;
-                    ref_tr.dispute()?
-                };
-                self.available -= amount_change;
-                self.held += amount_change;
+                let mut status = self.get_transaction_status(tr)?.clone();
+                self.check_dispute_window(&status, tr, rules)?;
+                let portion = status.apply(DisputeAction::Dispute, tr.amount, rules)?;
+                let held_amount = status.held_amount(portion, rules)?;
+
+                self.update_balance(&status.currency, held_amount.neg(), held_amount)?;
+                self.transaction_status.insert(tr.transaction_id, status);
             }
             Resolve => {
                 tr.check_amount_empty(verbose);
-                let amount_change = {
-                    let ref_tr = self.get_transaction_status(tr.transaction_id)?;
-                    ref_tr.resolve()?
-                };
-                self.available += amount_change;
-                self.held -= amount_change;
+                let mut status = self.get_transaction_status(tr)?.clone();
+                let portion = status.apply(DisputeAction::Resolve, None, rules)?;
+                let held_amount = status.held_amount(portion, rules)?;
+
+                self.update_balance(&status.currency, held_amount, held_amount.neg())?;
+                self.transaction_status.insert(tr.transaction_id, status);
             }
             Chargeback => {
                 tr.check_amount_empty(verbose);
-                let amount_change = {
-                    let ref_tr = self.get_transaction_status(tr.transaction_id)?;
-                    ref_tr.chargeback()?
+                let mut status = self.get_transaction_status(tr)?.clone();
+                let portion = status.apply(DisputeAction::Chargeback, None, rules)?;
+                let held_amount = status.held_amount(portion, rules)?;
+
+                // whatever was not held during the dispute is taken from available
+                self.update_balance(
+                    &status.currency,
+                    (portion - held_amount).neg(),
+                    held_amount.neg(),
+                )?;
+                self.transaction_status.insert(tr.transaction_id, status);
+                self.locked = true;
+            }
+            // a returned deposit needs the funds like a withdrawal, a returned withdrawal is credited
+            Refund | Reversal => {
+                let mut status = self.get_transaction_status(tr)?.clone();
+                let portion = if tr.transaction_type == Refund {
+                    status.refund(tr.amount)?
+                } else {
+                    tr.check_amount_empty(verbose);
+                    status.reverse()?
                 };
-                self.held -= amount_change;
+                if portion > Decimal::ZERO && self.balance(&status.currency).available < portion {
+                    return Err(Error::InsufficientFunds);
+                }
+
+                self.update_balance(&status.currency, portion.neg(), Decimal::ZERO)?;
+                self.transaction_status.insert(tr.transaction_id, status);
+            }
+            // sender side of a transfer, the engine credits the recipient with `prepare_receive` and `commit_receive`
+            Transfer => {
+                let amount = tr.get_amount()?;
+                if self.balance(tr.currency()).available < amount {
+                    return Err(Error::InsufficientFunds);
+                }
+
+                self.update_balance(tr.currency(), amount.neg(), Decimal::ZERO)?;
+            }
+            Unlock => {
+                tr.check_amount_empty(verbose);
+                self.locked = false;
+            }
+            Freeze => {
+                tr.check_amount_empty(verbose);
                 self.locked = true;
             }
+            Close => {
+                tr.check_amount_empty(verbose);
+                if !self.balances.values().all(Balance::is_empty) {
+                    return Err(Error::AccountNotEmpty);
+                }
+                self.closed = true;
+            }
+            Adjust => {
+                tr.get_reason()?;
+                let amount = tr.get_amount()?;
+                if self.balance(tr.currency()).available < amount.neg() {
+                    return Err(Error::InsufficientFunds);
+                }
+
+                self.update_balance(tr.currency(), amount, Decimal::ZERO)?;
+            }
         }
 
+        if tr.timestamp > self.last_timestamp {
+            self.last_timestamp = tr.timestamp;
+        }
+        self.prune_expired(rules);
         Ok(())
     }
 }
 
-pub fn process_all(transactions: Vec<Transaction>, verbose: bool) -> HashMap<ClientId, Account> {
-    let transactions_per_client: Vec<(ClientId, Vec<Transaction>)> = transactions
-        .into_iter()
-        .group_by(|t| t.client_id)
-        .into_iter()
-        .map(|(id, items)| (id, items.collect()))
-        .collect();
-
-    // using rayon to process clients in parallel
-    transactions_per_client.par_iter()
-        .map(|(cid, ctr)| {
-            let acc = Account::from_transactions(cid, ctr, verbose);
-            (cid.to_owned(), acc)
-        })
-        .collect()
+/// Rounding of every amount in the reports: 4 decimal places, midpoints away from zero.
+/// Zero is always printed as `0.0000`, whatever its sign and scale.
+pub fn round_output(value: Decimal) -> Decimal {
+    let rounded = value.round_dp_with_strategy(4, RoundingStrategy::MidpointAwayFromZero);
+    if rounded.is_zero() {
+        // rust_decimal versions differ in the scale they give a rounded zero
+        return Decimal::new(0, 4);
+    }
+    rounded
 }
 
 #[derive(Serialize, Clone, Debug, PartialEq)]
 pub struct AccountOutput {
     client: ClientId,
+    currency: Currency,
     available: Decimal,
     held: Decimal,
     total: Decimal,
     locked: bool,
+    fees_paid: Decimal,
+    credit_used: Decimal,
 }
 
-impl<'a> From<&'a Account> for AccountOutput {
-    fn from(a: &'a Account) -> Self {
+impl AccountOutput {
+    pub fn new(
+        client: ClientId,
+        currency: &str,
+        balance: &Balance,
+        locked: bool,
+        fees_paid: Decimal,
+        credit_used: Decimal,
+    ) -> AccountOutput {
         AccountOutput {
-            client: a.client_id,
-            available: a
-                .available
-                .round_dp_with_strategy(4, RoundingStrategy::MidpointAwayFromZero),
-            held: a
-                .held
-                .round_dp_with_strategy(4, RoundingStrategy::MidpointAwayFromZero),
-            total: a
-                .total()
-                .round_dp_with_strategy(4, RoundingStrategy::MidpointAwayFromZero),
-            locked: a.locked,
+            client,
+            currency: currency.to_string(),
+            available: round_output(balance.available),
+            held: round_output(balance.held),
+            total: round_output(balance.total()),
+            locked,
+            fees_paid: round_output(fees_paid),
+            credit_used: round_output(credit_used),
+        }
+    }
+
+    /// One row per currency used by the account. An account without any balance gets an empty row
+    /// in the default currency, so every client is listed.
+    pub fn from_account(a: &Account) -> Vec<AccountOutput> {
+        if a.balances.is_empty() {
+            return vec![AccountOutput::new(
+                a.client_id,
+                DEFAULT_CURRENCY,
+                &Balance::default(),
+                a.locked,
+                Decimal::ZERO,
+                Decimal::ZERO,
+            )];
+        }
+        a.balances
+            .iter()
+            .map(|(currency, balance)| {
+                AccountOutput::new(
+                    a.client_id,
+                    currency,
+                    balance,
+                    a.locked,
+                    a.fees_paid(currency),
+                    a.credit_used(currency),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Row order of the account output. Ties are always broken by client ID and currency, so the output is deterministic.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputOrder {
+    Client,
+    TotalDesc,
+    LockedFirst,
+}
+
+impl OutputOrder {
+    pub const VALUES: [&'static str; 3] = ["client", "total-desc", "locked-first"];
+
+    pub fn sort(self, accounts: &mut [AccountOutput]) {
+        match self {
+            OutputOrder::Client => accounts.sort_by(|a, b| (a.client, &a.currency).cmp(&(b.client, &b.currency))),
+            OutputOrder::TotalDesc => accounts.sort_by(|a, b| {
+                b.total
+                    .cmp(&a.total)
+                    .then((a.client, &a.currency).cmp(&(b.client, &b.currency)))
+            }),
+            OutputOrder::LockedFirst => accounts.sort_by(|a, b| {
+                (!a.locked, a.client, &a.currency).cmp(&(!b.locked, b.client, &b.currency))
+            }),
+        }
+    }
+}
+
+impl FromStr for OutputOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(OutputOrder::Client),
+            "total-desc" => Ok(OutputOrder::TotalDesc),
+            "locked-first" => Ok(OutputOrder::LockedFirst),
+            _ => Err(format!("unknown output order: {}", s)),
         }
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_new() {
         let acc = Account::new(5);
-        assert_eq!(acc.held, Decimal::ZERO);
-        assert_eq!(acc.available, Decimal::ZERO);
+        assert_eq!(acc.held(), Decimal::ZERO);
+        assert_eq!(acc.available(), Decimal::ZERO);
         assert_eq!(acc.total(), Decimal::ZERO);
     }
 
@@ -230,6 +760,10 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: Some(Decimal::new(123456, 2)),
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
@@ -247,13 +781,17 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: Some(Decimal::new(123456, 2)),
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
         assert!(res.is_ok(), "processing error: {:?}", res);
 
-        assert_eq!(acc.held, Decimal::ZERO);
-        assert_eq!(acc.available, Decimal::new(123456, 2));
+        assert_eq!(acc.held(), Decimal::ZERO);
+        assert_eq!(acc.available(), Decimal::new(123456, 2));
         assert_eq!(acc.total(), Decimal::new(123456, 2));
         assert_eq!(acc.locked, false);
     }
@@ -267,6 +805,10 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: Some(Decimal::new(123456, 2)),
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
@@ -278,6 +820,10 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: Some(Decimal::new(3456, 2)),
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
@@ -295,6 +841,10 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: Some(Decimal::new(123456, 2)),
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
@@ -305,13 +855,17 @@ mod tests {
                 client_id: 5,
                 transaction_id: 2,
                 amount: Some(Decimal::new(3456, 2)),
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
         assert!(res.is_ok(), "withdraw error: {:?}", res);
 
-        assert_eq!(acc.held, Decimal::ZERO);
-        assert_eq!(acc.available, Decimal::new(1200, 0));
+        assert_eq!(acc.held(), Decimal::ZERO);
+        assert_eq!(acc.available(), Decimal::new(1200, 0));
         assert_eq!(acc.total(), Decimal::new(1200, 0));
         assert_eq!(acc.locked, false);
     }
@@ -325,6 +879,10 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: Some(Decimal::new(123456, 2)),
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
@@ -335,6 +893,10 @@ mod tests {
                 client_id: 5,
                 transaction_id: 2,
                 amount: Some(Decimal::new(11113456, 2)),
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
@@ -352,6 +914,10 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: Some(Decimal::new(123456, 2)),
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
@@ -362,13 +928,17 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: None,
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
         assert!(res.is_ok(), "dispute error: {:?}", res);
 
-        assert_eq!(acc.held, Decimal::new(123456, 2));
-        assert_eq!(acc.available, Decimal::ZERO);
+        assert_eq!(acc.held(), Decimal::new(123456, 2));
+        assert_eq!(acc.available(), Decimal::ZERO);
         assert_eq!(acc.total(), Decimal::new(123456, 2));
         assert_eq!(acc.locked, false);
     }
@@ -382,6 +952,10 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: Some(Decimal::new(123456, 2)),
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
@@ -392,6 +966,10 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: None,
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
@@ -402,13 +980,17 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: None,
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
         assert_eq!(res, Err(Error::AlreadyDisputed), "double dispute should fail");
 
-        assert_eq!(acc.held, Decimal::new(123456, 2));
-        assert_eq!(acc.available, Decimal::ZERO);
+        assert_eq!(acc.held(), Decimal::new(123456, 2));
+        assert_eq!(acc.available(), Decimal::ZERO);
         assert_eq!(acc.total(), Decimal::new(123456, 2));
         assert_eq!(acc.locked, false);
     }
@@ -422,6 +1004,10 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: Some(Decimal::new(123456, 2)),
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
@@ -432,6 +1018,10 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: None,
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
@@ -442,13 +1032,17 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: None,
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
         assert!(res.is_ok(), "resolve error: {:?}", res);
 
-        assert_eq!(acc.held, Decimal::ZERO);
-        assert_eq!(acc.available, Decimal::new(123456, 2));
+        assert_eq!(acc.held(), Decimal::ZERO);
+        assert_eq!(acc.available(), Decimal::new(123456, 2));
         assert_eq!(acc.total(), Decimal::new(123456, 2));
         assert_eq!(acc.locked, false);
     }
@@ -462,6 +1056,10 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: Some(Decimal::new(123456, 2)),
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
@@ -472,6 +1070,10 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: None,
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
@@ -482,6 +1084,10 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: None,
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
@@ -492,17 +1098,93 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: None,
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
         assert!(res.is_ok(), "second dispute error: {:?}", res);
 
-        assert_eq!(acc.held, Decimal::new(123456, 2));
-        assert_eq!(acc.available, Decimal::ZERO);
+        assert_eq!(acc.held(), Decimal::new(123456, 2));
+        assert_eq!(acc.available(), Decimal::ZERO);
         assert_eq!(acc.total(), Decimal::new(123456, 2));
         assert_eq!(acc.locked, false);
     }
 
+    #[test]
+    fn test_dispute_transitions() {
+        use DisputeAction::*;
+        use DisputeState::*;
+
+        let rules = DisputeRules::default();
+        assert_eq!(Settled.next(Dispute, &rules), Ok(Disputed));
+        assert_eq!(Settled.next(Resolve, &rules), Err(Error::NotDisputed));
+        assert_eq!(Settled.next(Chargeback, &rules), Err(Error::NotDisputed));
+        assert_eq!(Disputed.next(Dispute, &rules), Err(Error::AlreadyDisputed));
+        assert_eq!(Disputed.next(Resolve, &rules), Ok(Resolved));
+        assert_eq!(Disputed.next(Chargeback, &rules), Ok(ChargedBack));
+        assert_eq!(Resolved.next(Dispute, &rules), Ok(Disputed));
+        assert_eq!(Resolved.next(Resolve, &rules), Err(Error::NotDisputed));
+        assert_eq!(Resolved.next(Chargeback, &rules), Err(Error::NotDisputed));
+        for action in [Dispute, Resolve, Chargeback].iter() {
+            assert_eq!(ChargedBack.next(*action, &rules), Err(Error::AlreadyChargedBack));
+        }
+
+        let strict = DisputeRules {
+            allow_redispute: false,
+            ..DisputeRules::default()
+        };
+        assert_eq!(Resolved.next(Dispute, &strict), Err(Error::AlreadyResolved));
+    }
+
+    #[test]
+    fn test_redispute_not_allowed() {
+        let rules = DisputeRules {
+            allow_redispute: false,
+            ..DisputeRules::default()
+        };
+        let mut acc = Account::new(5);
+        let steps = [
+            (TransactionType::Deposit, Some(Decimal::new(123456, 2))),
+            (TransactionType::Dispute, None),
+            (TransactionType::Resolve, None),
+        ];
+        for (transaction_type, amount) in steps.iter() {
+            let tr = Transaction {
+                transaction_type: transaction_type.clone(),
+                client_id: 5,
+                transaction_id: 1,
+                amount: *amount,
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
+            };
+            assert_eq!(acc.process_with(&tr, &rules, false), Ok(()));
+        }
+
+        let res = acc.process_with(
+            &Transaction {
+                transaction_type: TransactionType::Dispute,
+                client_id: 5,
+                transaction_id: 1,
+                amount: None,
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
+            },
+            &rules,
+            false,
+        );
+        assert_eq!(res, Err(Error::AlreadyResolved), "second dispute should fail");
+        assert_eq!(acc.held(), Decimal::ZERO);
+        assert_eq!(acc.available(), Decimal::new(123456, 2));
+        assert_eq!(acc.transaction_status[&1].state, DisputeState::Resolved);
+    }
+
     #[test]
     fn test_dispute_chargeback() {
         let mut acc = Account::new(5);
@@ -512,6 +1194,10 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: Some(Decimal::new(123456, 2)),
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
@@ -522,6 +1208,10 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: None,
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
@@ -532,13 +1222,17 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: None,
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
         assert!(res.is_ok(), "chargeback error: {:?}", res);
 
-        assert_eq!(acc.held, Decimal::ZERO);
-        assert_eq!(acc.available, Decimal::ZERO);
+        assert_eq!(acc.held(), Decimal::ZERO);
+        assert_eq!(acc.available(), Decimal::ZERO);
         assert_eq!(acc.total(), Decimal::ZERO);
         assert_eq!(acc.locked, true);
     }
@@ -552,6 +1246,10 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: Some(Decimal::new(123456, 2)),
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
@@ -562,11 +1260,15 @@ mod tests {
                 client_id: 5,
                 transaction_id: 2,
                 amount: Some(Decimal::new(1111, 2)),
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
         assert!(res.is_ok(), "withdrawal error: {:?}", res);
-        assert_eq!(acc.available, Decimal::new(122345, 2));
+        assert_eq!(acc.available(), Decimal::new(122345, 2));
         assert_eq!(acc.total(), Decimal::new(122345, 2));
         let res = acc.process(
             &Transaction {
@@ -574,12 +1276,16 @@ mod tests {
                 client_id: 5,
                 transaction_id: 2,
                 amount: None,
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
         assert!(res.is_ok(), "dispute error: {:?}", res);
-        assert_eq!(acc.available, Decimal::new(123456, 2));
-        assert_eq!(acc.held, Decimal::new(-1111, 2));
+        assert_eq!(acc.available(), Decimal::new(123456, 2));
+        assert_eq!(acc.held(), Decimal::new(-1111, 2));
         assert_eq!(acc.total(), Decimal::new(122345, 2));
         let res = acc.process(
             &Transaction {
@@ -587,17 +1293,777 @@ mod tests {
                 client_id: 5,
                 transaction_id: 2,
                 amount: None,
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
         assert!(res.is_ok(), "chargeback error: {:?}", res);
 
-        assert_eq!(acc.held, Decimal::ZERO);
-        assert_eq!(acc.available, Decimal::new(123456, 2));
+        assert_eq!(acc.held(), Decimal::ZERO);
+        assert_eq!(acc.available(), Decimal::new(123456, 2));
         assert_eq!(acc.total(), Decimal::new(123456, 2));
         assert_eq!(acc.locked, true);
     }
 
+    fn withdrawal_dispute_steps(acc: &mut Account, rules: &DisputeRules, steps: &[TransactionType]) -> Result<()> {
+        for transaction_type in steps {
+            acc.process_with(
+                &Transaction {
+                    transaction_type: transaction_type.clone(),
+                    client_id: 5,
+                    transaction_id: 2,
+                    amount: None,
+                    reason: None,
+                    currency: None,
+                    timestamp: None,
+                    recipient: None,
+                },
+                rules,
+                false,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn account_with_withdrawal() -> Account {
+        let mut acc = Account::new(5);
+        for (transaction_type, transaction_id, amount) in [
+            (TransactionType::Deposit, 1, Decimal::new(123456, 2)),
+            (TransactionType::Withdrawal, 2, Decimal::new(1111, 2)),
+        ]
+        .iter()
+        {
+            let res = acc.process(
+                &Transaction {
+                    transaction_type: transaction_type.clone(),
+                    client_id: 5,
+                    transaction_id: *transaction_id,
+                    amount: Some(*amount),
+                    reason: None,
+                    currency: None,
+                    timestamp: None,
+                    recipient: None,
+                },
+                false,
+            );
+            assert!(res.is_ok(), "{:?} error: {:?}", transaction_type, res);
+        }
+        acc
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_forbidden() {
+        let rules = DisputeRules {
+            withdrawals: WithdrawalDisputes::Forbid,
+            ..DisputeRules::default()
+        };
+        let mut acc = account_with_withdrawal();
+
+        let res = withdrawal_dispute_steps(&mut acc, &rules, &[TransactionType::Dispute]);
+        assert_eq!(res, Err(Error::WithdrawalDisputeForbidden));
+        assert_eq!(acc.available(), Decimal::new(122345, 2));
+        assert_eq!(acc.held(), Decimal::ZERO);
+        assert_eq!(acc.transaction_status[&2].state, DisputeState::Settled);
+
+        let res = acc.process_with(
+            &Transaction {
+                transaction_type: TransactionType::Dispute,
+                client_id: 5,
+                transaction_id: 1,
+                amount: None,
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
+            },
+            &rules,
+            false,
+        );
+        assert!(res.is_ok(), "deposits can still be disputed: {:?}", res);
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_symmetric() {
+        let rules = DisputeRules::default();
+        let mut acc = account_with_withdrawal();
+
+        let res = withdrawal_dispute_steps(&mut acc, &rules, &[TransactionType::Dispute]);
+        assert!(res.is_ok(), "dispute error: {:?}", res);
+        assert_eq!(acc.available(), Decimal::new(123456, 2));
+        assert_eq!(acc.held(), Decimal::new(-1111, 2));
+
+        let res = withdrawal_dispute_steps(&mut acc, &rules, &[TransactionType::Resolve]);
+        assert!(res.is_ok(), "resolve error: {:?}", res);
+        assert_eq!(acc.available(), Decimal::new(122345, 2));
+        assert_eq!(acc.held(), Decimal::ZERO);
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_hold_without_credit() {
+        let rules = DisputeRules {
+            withdrawals: WithdrawalDisputes::HoldWithoutCredit,
+            ..DisputeRules::default()
+        };
+        let mut acc = account_with_withdrawal();
+
+        let res = withdrawal_dispute_steps(&mut acc, &rules, &[TransactionType::Dispute, TransactionType::Resolve]);
+        assert!(res.is_ok(), "dispute error: {:?}", res);
+        assert_eq!(acc.available(), Decimal::new(122345, 2), "nothing should be credited");
+        assert_eq!(acc.held(), Decimal::ZERO, "nothing should be held");
+
+        let res = withdrawal_dispute_steps(&mut acc, &rules, &[TransactionType::Dispute]);
+        assert!(res.is_ok(), "second dispute error: {:?}", res);
+        assert_eq!(acc.available(), Decimal::new(122345, 2));
+        assert_eq!(acc.held(), Decimal::ZERO);
+
+        let res = withdrawal_dispute_steps(&mut acc, &rules, &[TransactionType::Chargeback]);
+        assert!(res.is_ok(), "chargeback error: {:?}", res);
+        assert_eq!(acc.available(), Decimal::new(123456, 2), "chargeback should credit the withdrawal");
+        assert_eq!(acc.held(), Decimal::ZERO);
+        assert!(acc.locked);
+    }
+
+    fn admin(transaction_type: TransactionType, transaction_id: TransactionId, amount: Option<Decimal>) -> Transaction {
+        Transaction {
+            transaction_type,
+            client_id: 5,
+            transaction_id,
+            amount,
+            reason: Some("SUPPORT-1".to_string()),
+            currency: None,
+            timestamp: None,
+            recipient: None,
+        }
+    }
+
+    #[test]
+    fn test_unlock_after_chargeback() {
+        let mut acc = Account::new(5);
+        for (transaction_type, amount) in [
+            (TransactionType::Deposit, Some(Decimal::new(100, 0))),
+            (TransactionType::Dispute, None),
+            (TransactionType::Chargeback, None),
+        ]
+        .iter()
+        {
+            let res = acc.process(
+                &Transaction {
+                    transaction_type: transaction_type.clone(),
+                    client_id: 5,
+                    transaction_id: 1,
+                    amount: *amount,
+                    reason: None,
+                    currency: None,
+                    timestamp: None,
+                    recipient: None,
+                },
+                false,
+            );
+            assert!(res.is_ok(), "{:?} error: {:?}", transaction_type, res);
+        }
+        assert!(acc.locked);
+
+        let res = acc.process(&admin(TransactionType::Unlock, 2, None), false);
+        assert!(res.is_ok(), "unlock error: {:?}", res);
+        assert!(!acc.locked);
+
+        let res = acc.process(&admin(TransactionType::Deposit, 3, Some(Decimal::new(5, 0))), false);
+        assert!(res.is_ok(), "deposit after unlock error: {:?}", res);
+        assert_eq!(acc.available(), Decimal::new(5, 0));
+
+        let res = acc.process(
+            &Transaction {
+                transaction_type: TransactionType::Dispute,
+                client_id: 5,
+                transaction_id: 1,
+                amount: None,
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
+            },
+            false,
+        );
+        assert_eq!(res, Err(Error::AlreadyChargedBack), "charged back transaction cannot be disputed");
+    }
+
+    #[test]
+    fn test_freeze() {
+        let mut acc = Account::new(5);
+        let res = acc.process(&admin(TransactionType::Freeze, 1, None), false);
+        assert!(res.is_ok(), "freeze error: {:?}", res);
+        assert!(acc.locked);
+
+        let res = acc.process(&admin(TransactionType::Deposit, 2, Some(Decimal::new(5, 0))), false);
+        assert_eq!(res, Err(Error::AccountLocked), "frozen account should reject deposits");
+    }
+
+    #[test]
+    fn test_close() {
+        let mut acc = Account::new(5);
+        let res = acc.process(&admin(TransactionType::Deposit, 1, Some(Decimal::new(5, 0))), false);
+        assert!(res.is_ok(), "deposit error: {:?}", res);
+
+        let res = acc.process(&admin(TransactionType::Close, 2, None), false);
+        assert_eq!(res, Err(Error::AccountNotEmpty), "account with funds cannot be closed");
+
+        let res = acc.process(&admin(TransactionType::Withdrawal, 3, Some(Decimal::new(5, 0))), false);
+        assert!(res.is_ok(), "withdrawal error: {:?}", res);
+        let res = acc.process(&admin(TransactionType::Close, 4, None), false);
+        assert!(res.is_ok(), "close error: {:?}", res);
+        assert!(acc.closed);
+
+        let res = acc.process(&admin(TransactionType::Unlock, 5, None), false);
+        assert_eq!(res, Err(Error::AccountClosed));
+    }
+
+    #[test]
+    fn test_adjust() {
+        let mut acc = Account::new(5);
+        let res = acc.process(&admin(TransactionType::Freeze, 1, None), false);
+        assert!(res.is_ok(), "freeze error: {:?}", res);
+
+        let res = acc.process(&admin(TransactionType::Adjust, 2, Some(Decimal::new(25, 1))), false);
+        assert!(res.is_ok(), "adjust of locked account error: {:?}", res);
+        assert_eq!(acc.available(), Decimal::new(25, 1));
+
+        let res = acc.process(&admin(TransactionType::Adjust, 3, Some(Decimal::new(-3, 0))), false);
+        assert_eq!(res, Err(Error::InsufficientFunds));
+
+        let mut no_reason = admin(TransactionType::Adjust, 4, Some(Decimal::new(1, 0)));
+        no_reason.reason = None;
+        assert_eq!(acc.process(&no_reason, false), Err(Error::MissingReason));
+        assert_eq!(acc.available(), Decimal::new(25, 1));
+    }
+
+    #[test]
+    fn test_balance_overflow() {
+        let mut acc = Account::new(5);
+        let max = Some(Decimal::MAX);
+        let res = acc.process(&admin(TransactionType::Deposit, 1, max), false);
+        assert!(res.is_ok(), "deposit error: {:?}", res);
+
+        let res = acc.process(&admin(TransactionType::Deposit, 2, Some(Decimal::ONE)), false);
+        assert_eq!(res, Err(Error::BalanceOverflow));
+        assert_eq!(acc.available(), Decimal::MAX);
+
+        for (id, transaction_type, amount) in [
+            (3, TransactionType::Withdrawal, max),
+            (4, TransactionType::Deposit, max),
+            (1, TransactionType::Dispute, None),
+        ]
+        .iter()
+        {
+            let res = acc.process(&admin(transaction_type.clone(), *id, *amount), false);
+            assert!(res.is_ok(), "{:?} error: {:?}", transaction_type, res);
+        }
+
+        // total of held and available would overflow
+        let res = acc.process(&admin(TransactionType::Dispute, 4, None), false);
+        assert_eq!(res, Err(Error::BalanceOverflow));
+        assert_eq!((acc.available(), acc.held()), (Decimal::ZERO, Decimal::MAX));
+
+        let res = acc.process(&admin(TransactionType::Resolve, 4, None), false);
+        assert_eq!(res, Err(Error::NotDisputed), "failed dispute should not change the dispute state");
+    }
+
+    fn in_currency(
+        transaction_type: TransactionType,
+        transaction_id: TransactionId,
+        amount: Option<Decimal>,
+        currency: Option<&str>,
+    ) -> Transaction {
+        Transaction {
+            transaction_type,
+            client_id: 5,
+            transaction_id,
+            amount,
+            reason: None,
+            currency: currency.map(String::from),
+            timestamp: None,
+            recipient: None,
+        }
+    }
+
+    #[test]
+    fn test_currencies_are_separate() {
+        let mut acc = Account::new(5);
+        let usd = Some("USD");
+        for tr in [
+            in_currency(TransactionType::Deposit, 1, Some(Decimal::new(10, 0)), None),
+            in_currency(TransactionType::Deposit, 2, Some(Decimal::new(3, 0)), usd),
+        ]
+        .iter()
+        {
+            let res = acc.process(tr, false);
+            assert!(res.is_ok(), "{:?} error: {:?}", tr, res);
+        }
+
+        let withdrawal = in_currency(TransactionType::Withdrawal, 3, Some(Decimal::new(5, 0)), usd);
+        assert_eq!(acc.process(&withdrawal, false), Err(Error::InsufficientFunds), "EUR funds are not available in USD");
+
+        let res = acc.process(&in_currency(TransactionType::Dispute, 2, None, None), false);
+        assert!(res.is_ok(), "dispute error: {:?}", res);
+        assert_eq!(acc.balance("USD").held, Decimal::new(3, 0), "dispute should use the currency of the deposit");
+        assert_eq!(acc.balance("USD").available, Decimal::ZERO);
+        assert_eq!(acc.balance(DEFAULT_CURRENCY).available, Decimal::new(10, 0));
+
+        let resolve = in_currency(TransactionType::Resolve, 2, None, Some("GBP"));
+        assert_eq!(acc.process(&resolve, false), Err(Error::CurrencyMismatch));
+
+        let outputs = AccountOutput::from_account(&acc);
+        let currencies: Vec<&str> = outputs.iter().map(|o| o.currency.as_str()).collect();
+        assert_eq!(currencies, vec!["EUR", "USD"]);
+    }
+
+    #[test]
+    fn test_check_order() {
+        let mut acc = Account::new(5);
+        let at = |tx, time| at(TransactionType::Deposit, tx, Some(Decimal::ONE), time);
+
+        let first = at(1, "2024-03-01T10:00:00Z");
+        assert_eq!(acc.check_order(&first), Ok(()));
+        acc.process(&first, false).unwrap();
+        assert_eq!(acc.last_timestamp(), first.timestamp);
+        assert_eq!(acc.transaction_status[&1].timestamp, first.timestamp);
+
+        assert_eq!(acc.check_order(&at(2, "2024-03-01T09:59:59Z")), Err(Error::OutOfOrder));
+        assert_eq!(acc.check_order(&at(2, "2024-03-01T10:00:00Z")), Ok(()), "equal timestamps are in order");
+        let untimed = in_currency(TransactionType::Deposit, 2, Some(Decimal::ONE), None);
+        assert_eq!(acc.check_order(&untimed), Ok(()));
+        acc.process(&untimed, false).unwrap();
+        assert_eq!(acc.last_timestamp(), first.timestamp);
+    }
+
+    #[test]
+    fn test_partial_disputes() {
+        let mut acc = Account::new(5);
+        let tr = |transaction_type, amount: Option<i64>| {
+            in_currency(transaction_type, 1, amount.map(|a| Decimal::new(a, 0)), None)
+        };
+        let balances = |acc: &Account| (acc.available(), acc.held());
+
+        acc.process(&tr(TransactionType::Deposit, Some(100)), false).unwrap();
+        acc.process(&tr(TransactionType::Dispute, Some(30)), false).unwrap();
+        assert_eq!(balances(&acc), (Decimal::new(70, 0), Decimal::new(30, 0)));
+        assert_eq!(acc.transaction_status[&1].disputed, Decimal::new(30, 0));
+
+        acc.process(&tr(TransactionType::Dispute, Some(50)), false).unwrap();
+        assert_eq!(balances(&acc), (Decimal::new(20, 0), Decimal::new(80, 0)));
+        assert_eq!(
+            acc.process(&tr(TransactionType::Dispute, Some(21)), false),
+            Err(Error::DisputedAmountExceeded)
+        );
+        assert_eq!(
+            acc.process(&tr(TransactionType::Dispute, None), false),
+            Err(Error::AlreadyDisputed),
+            "the whole amount cannot be disputed again"
+        );
+
+        acc.process(&tr(TransactionType::Resolve, None), false).unwrap();
+        assert_eq!(balances(&acc), (Decimal::new(100, 0), Decimal::ZERO));
+        assert_eq!(acc.transaction_status[&1].disputed, Decimal::ZERO);
+
+        acc.process(&tr(TransactionType::Dispute, Some(40)), false).unwrap();
+        acc.process(&tr(TransactionType::Chargeback, None), false).unwrap();
+        assert_eq!(balances(&acc), (Decimal::new(60, 0), Decimal::ZERO));
+        assert!(acc.is_locked());
+    }
+
+    #[test]
+    fn test_partial_withdrawal_disputes() {
+        let dispute = |amount| Transaction {
+            transaction_type: TransactionType::Dispute,
+            client_id: 5,
+            transaction_id: 2,
+            amount: Some(amount),
+            reason: None,
+            currency: None,
+            timestamp: None,
+            recipient: None,
+        };
+        let chargeback = Transaction {
+            transaction_type: TransactionType::Chargeback,
+            amount: None,
+            ..dispute(Decimal::ZERO)
+        };
+
+        let mut acc = account_with_withdrawal();
+        acc.process(&dispute(Decimal::new(1000, 2)), false).unwrap();
+        assert_eq!(acc.held(), Decimal::new(-1000, 2));
+        assert_eq!(
+            acc.process(&dispute(Decimal::new(112, 2)), false),
+            Err(Error::DisputedAmountExceeded)
+        );
+        acc.process(&chargeback, false).unwrap();
+        assert_eq!(acc.held(), Decimal::ZERO);
+        assert_eq!(acc.available(), Decimal::new(123456 - 111, 2));
+
+        let rules = DisputeRules {
+            withdrawals: WithdrawalDisputes::HoldWithoutCredit,
+            ..DisputeRules::default()
+        };
+        let mut acc = account_with_withdrawal();
+        acc.process_with(&dispute(Decimal::new(1000, 2)), &rules, false).unwrap();
+        assert_eq!(acc.held(), Decimal::ZERO);
+        acc.process_with(&chargeback, &rules, false).unwrap();
+        assert_eq!(acc.available(), Decimal::new(123456 - 111, 2), "only the disputed portion is credited");
+    }
+
+    #[test]
+    fn test_refund() {
+        let mut acc = Account::new(5);
+        let tr = |transaction_type, tx, amount: Option<i64>| {
+            in_currency(transaction_type, tx, amount.map(|a| Decimal::new(a, 0)), None)
+        };
+
+        acc.process(&tr(TransactionType::Deposit, 1, Some(100)), false).unwrap();
+        acc.process(&tr(TransactionType::Refund, 1, Some(30)), false).unwrap();
+        assert_eq!(acc.available(), Decimal::new(70, 0));
+        assert_eq!(acc.transaction_status[&1].refunded, Decimal::new(30, 0));
+        assert_eq!(
+            acc.process(&tr(TransactionType::Refund, 1, Some(71)), false),
+            Err(Error::RefundedAmountExceeded)
+        );
+
+        // a full dispute covers only the part which was not refunded
+        acc.process(&tr(TransactionType::Dispute, 1, None), false).unwrap();
+        assert_eq!(acc.held(), Decimal::new(70, 0));
+        assert_eq!(
+            acc.process(&tr(TransactionType::Refund, 1, Some(10)), false),
+            Err(Error::AlreadyDisputed)
+        );
+        acc.process(&tr(TransactionType::Resolve, 1, None), false).unwrap();
+
+        acc.process(&tr(TransactionType::Withdrawal, 2, Some(50)), false).unwrap();
+        assert_eq!(
+            acc.process(&tr(TransactionType::Refund, 1, None), false),
+            Err(Error::InsufficientFunds)
+        );
+        acc.process(&tr(TransactionType::Refund, 2, Some(50)), false).unwrap();
+        assert_eq!(acc.available(), Decimal::new(70, 0), "a refunded withdrawal is credited");
+
+        acc.process(&tr(TransactionType::Refund, 1, None), false).unwrap();
+        assert_eq!(acc.available(), Decimal::ZERO);
+        assert_eq!(
+            acc.process(&tr(TransactionType::Dispute, 1, None), false),
+            Err(Error::DisputedAmountExceeded),
+            "nothing is left to dispute"
+        );
+        assert!(!acc.is_locked());
+    }
+
+    #[test]
+    fn test_reversal() {
+        let mut acc = Account::new(5);
+        let tr = |transaction_type, tx, amount: Option<i64>| {
+            in_currency(transaction_type, tx, amount.map(|a| Decimal::new(a, 0)), None)
+        };
+
+        acc.process(&tr(TransactionType::Deposit, 1, Some(100)), false).unwrap();
+        acc.process(&tr(TransactionType::Deposit, 2, Some(10)), false).unwrap();
+        acc.process(&tr(TransactionType::Refund, 1, Some(40)), false).unwrap();
+        acc.process(&tr(TransactionType::Reversal, 1, None), false).unwrap();
+        assert_eq!(acc.available(), Decimal::new(10, 0));
+        assert_eq!(acc.transaction_status[&1].state, DisputeState::Reversed);
+        assert!(!acc.is_locked());
+
+        for transaction_type in [TransactionType::Reversal, TransactionType::Refund, TransactionType::Dispute].iter() {
+            assert_eq!(
+                acc.process(&tr(transaction_type.clone(), 1, None), false),
+                Err(Error::AlreadyReversed),
+                "{:?} after reversal",
+                transaction_type
+            );
+        }
+
+        acc.process(&tr(TransactionType::Dispute, 2, None), false).unwrap();
+        assert_eq!(
+            acc.process(&tr(TransactionType::Reversal, 2, None), false),
+            Err(Error::AlreadyDisputed)
+        );
+        acc.process(&tr(TransactionType::Chargeback, 2, None), false).unwrap();
+        acc.process(&tr(TransactionType::Unlock, 0, None), false).unwrap();
+        assert_eq!(
+            acc.process(&tr(TransactionType::Reversal, 2, None), false),
+            Err(Error::AlreadyChargedBack)
+        );
+    }
+
+    #[test]
+    fn test_transfer() {
+        let transfer = Transaction {
+            recipient: Some(6),
+            ..in_currency(TransactionType::Transfer, 2, Some(Decimal::new(30, 0)), None)
+        };
+        let mut sender = Account::new(5);
+        let mut recipient = Account::new(6);
+        assert_eq!(sender.process(&transfer, false), Err(Error::InsufficientFunds));
+
+        sender.process(&in_currency(TransactionType::Deposit, 1, Some(Decimal::new(100, 0)), None), false).unwrap();
+        let balance = recipient.prepare_receive(&transfer).unwrap();
+        assert_eq!(recipient.available(), Decimal::ZERO, "preparing changes nothing");
+        sender.process(&transfer, false).unwrap();
+        recipient.commit_receive(&transfer, balance);
+        assert_eq!(sender.available(), Decimal::new(70, 0));
+        assert_eq!(recipient.available(), Decimal::new(30, 0));
+
+        let dispute = Transaction {
+            client_id: 6,
+            ..in_currency(TransactionType::Dispute, 2, None, None)
+        };
+        assert_eq!(recipient.process(&dispute, false), Err(Error::UnknownTransactionId), "transfers cannot be disputed");
+
+        recipient.locked = true;
+        assert_eq!(recipient.prepare_receive(&transfer), Err(Error::AccountLocked));
+        recipient.locked = false;
+        recipient.closed = true;
+        assert_eq!(recipient.prepare_receive(&transfer), Err(Error::AccountClosed));
+    }
+
+    #[test]
+    fn test_credit_limit() {
+        let mut acc = Account::new(5);
+        acc.set_credit_limits(vec![(DEFAULT_CURRENCY.to_string(), Decimal::new(50, 0))].into_iter().collect());
+        acc.process(&in_currency(TransactionType::Deposit, 1, Some(Decimal::new(10, 0)), None), false).unwrap();
+
+        acc.process(&in_currency(TransactionType::Withdrawal, 2, Some(Decimal::new(40, 0)), None), false).unwrap();
+        assert_eq!(acc.available(), Decimal::new(-30, 0));
+        assert_eq!(acc.credit_used(DEFAULT_CURRENCY), Decimal::new(30, 0));
+
+        let withdrawal = in_currency(TransactionType::Withdrawal, 3, Some(Decimal::new(21, 0)), None);
+        assert_eq!(acc.process(&withdrawal, false), Err(Error::CreditLimitExceeded));
+        let withdrawal = in_currency(TransactionType::Withdrawal, 3, Some(Decimal::new(1, 0)), Some("USD"));
+        assert_eq!(acc.process(&withdrawal, false), Err(Error::InsufficientFunds), "no credit in other currencies");
+        let transfer = Transaction {
+            recipient: Some(6),
+            ..in_currency(TransactionType::Transfer, 3, Some(Decimal::new(1, 0)), None)
+        };
+        assert_eq!(acc.process(&transfer, false), Err(Error::InsufficientFunds), "only withdrawals use the credit line");
+
+        acc.process(&in_currency(TransactionType::Withdrawal, 3, Some(Decimal::new(20, 0)), None), false).unwrap();
+        assert_eq!(acc.available(), Decimal::new(-50, 0));
+        acc.process(&in_currency(TransactionType::Dispute, 1, None, None), false).unwrap();
+        assert_eq!(acc.credit_used(DEFAULT_CURRENCY), Decimal::new(50, 0), "used credit is capped by the limit");
+
+        let output = AccountOutput::from_account(&acc);
+        assert_eq!(output[0].credit_used, Decimal::new(50, 0));
+    }
+
+    #[test]
+    fn test_process_charged() {
+        let rules = DisputeRules::default();
+        let fee = Decimal::ONE;
+        let mut acc = Account::new(5);
+        let deposit = in_currency(TransactionType::Deposit, 1, Some(Decimal::new(10, 0)), Some("EUR"));
+        assert_eq!(acc.fee_base(&deposit), Decimal::new(10, 0));
+        acc.process_charged(&deposit, &rules, fee, false).unwrap();
+        assert_eq!(acc.balance("EUR").available, Decimal::new(9, 0));
+        assert_eq!(acc.fees_paid("EUR"), fee);
+
+        let withdrawal = in_currency(TransactionType::Withdrawal, 2, Some(Decimal::new(9, 0)), Some("EUR"));
+        let res = acc.process_charged(&withdrawal, &rules, fee, false);
+        assert_eq!(res, Err(Error::InsufficientFunds), "the fee needs funds too");
+        assert_eq!(acc.balance("EUR").available, Decimal::new(9, 0), "the fee is not taken on failure");
+        assert_eq!(acc.fees_paid("EUR"), fee);
+
+        let withdrawal = in_currency(TransactionType::Withdrawal, 3, Some(Decimal::ONE), Some("USD"));
+        assert_eq!(acc.process_charged(&withdrawal, &rules, fee, false), Err(Error::InsufficientFunds));
+        assert!(!acc.balances.contains_key("USD"), "no balance is left behind on failure");
+
+        let dispute = in_currency(TransactionType::Dispute, 1, None, None);
+        assert_eq!(acc.fee_base(&dispute), Decimal::new(10, 0));
+        acc.process_charged(&dispute, &rules, fee, false).unwrap();
+        assert_eq!(acc.balance("EUR").available, Decimal::new(-2, 0), "disputes charge the disputed currency");
+        assert_eq!(acc.fees_paid("EUR"), Decimal::new(2, 0));
+
+        let resolve = in_currency(TransactionType::Resolve, 1, None, None);
+        assert_eq!(acc.process_charged(&resolve, &rules, fee, false), Err(Error::InsufficientFunds));
+        assert_eq!(acc.held(), Decimal::new(10, 0), "the transaction is not processed without the fee");
+        assert_eq!(acc.process_charged(&resolve, &rules, Decimal::ZERO, false), Ok(()));
+    }
+
+    #[test]
+    fn test_fee_needs_funds() {
+        let rules = DisputeRules::default();
+        let mut acc = Account::new(5);
+        let deposit = in_currency(TransactionType::Deposit, 1, Some(Decimal::new(5, 0)), None);
+        let res = acc.process_charged(&deposit, &rules, Decimal::new(6, 0), false);
+        assert_eq!(res, Err(Error::InsufficientFunds), "fee larger than the deposit");
+        assert_eq!(acc.available(), Decimal::ZERO);
+        acc.process_charged(&deposit, &rules, Decimal::new(5, 0), false).unwrap();
+        assert_eq!(acc.available(), Decimal::ZERO, "the whole deposit pays the fee");
+
+        let deposit = in_currency(TransactionType::Deposit, 2, Some(Decimal::new(3, 0)), None);
+        acc.process_charged(&deposit, &rules, Decimal::ZERO, false).unwrap();
+        let dispute = in_currency(TransactionType::Dispute, 2, None, None);
+        assert_eq!(acc.process_charged(&dispute, &rules, Decimal::new(4, 0), false), Err(Error::InsufficientFunds));
+        assert_eq!((acc.available(), acc.held()), (Decimal::new(3, 0), Decimal::ZERO));
+
+        acc.set_credit_limits(vec![(DEFAULT_CURRENCY.to_string(), Decimal::ONE)].into_iter().collect());
+        acc.process_charged(&dispute, &rules, Decimal::new(4, 0), false).unwrap();
+        assert_eq!((acc.available(), acc.held()), (Decimal::new(-4, 0), Decimal::new(3, 0)), "the fee may use the credit line");
+        let refund = in_currency(TransactionType::Refund, 1, None, None);
+        assert_eq!(acc.process_charged(&refund, &rules, Decimal::ONE, false), Err(Error::CreditLimitExceeded));
+    }
+
+    fn at(transaction_type: TransactionType, tx: TransactionId, amount: Option<Decimal>, time: &str) -> Transaction {
+        Transaction {
+            timestamp: Some(time.parse().unwrap()),
+            ..in_currency(transaction_type, tx, amount, None)
+        }
+    }
+
+    #[test]
+    fn test_dispute_window() {
+        let rules = DisputeRules {
+            window: Some(Duration::days(30)),
+            ..DisputeRules::default()
+        };
+        let mut acc = Account::new(5);
+        for tr in [
+            at(TransactionType::Deposit, 1, Some(Decimal::ONE), "2024-03-01T10:00:00Z"),
+            at(TransactionType::Deposit, 2, Some(Decimal::ONE), "2024-03-20T10:00:00Z"),
+            in_currency(TransactionType::Deposit, 3, Some(Decimal::ONE), None),
+        ]
+        .iter()
+        {
+            acc.process_with(tr, &rules, false).unwrap();
+        }
+
+        let dispute = at(TransactionType::Dispute, 1, None, "2024-03-31T10:00:01Z");
+        assert_eq!(acc.process_with(&dispute, &rules, false), Err(Error::DisputeWindowExpired));
+        let dispute = at(TransactionType::Dispute, 1, None, "2024-03-31T10:00:00Z");
+        assert_eq!(acc.process_with(&dispute, &rules, false), Ok(()), "the last moment of the window");
+
+        let resolve = at(TransactionType::Resolve, 1, None, "2024-06-01T10:00:00Z");
+        assert_eq!(acc.process_with(&resolve, &rules, false), Ok(()), "only disputes have a window");
+
+        let untimed = in_currency(TransactionType::Dispute, 2, None, None);
+        assert_eq!(
+            acc.process_with(&untimed, &rules, false),
+            Err(Error::DisputeWindowExpired),
+            "the latest timestamp of the account is used"
+        );
+        let backdated = at(TransactionType::Dispute, 2, None, "2024-03-21T10:00:00Z");
+        assert_eq!(
+            acc.process_with(&backdated, &rules, false),
+            Err(Error::DisputeWindowExpired),
+            "a backdated dispute is checked against the latest timestamp of the account"
+        );
+        let dispute = at(TransactionType::Dispute, 3, None, "2025-01-01T10:00:00Z");
+        assert_eq!(acc.process_with(&dispute, &rules, false), Ok(()), "transactions without timestamp never expire");
+    }
+
+    #[test]
+    fn test_prune_expired() {
+        let rules = DisputeRules {
+            window: Some(Duration::days(10)),
+            prune_expired: true,
+            ..DisputeRules::default()
+        };
+        let mut acc = Account::new(5);
+        for tr in [
+            at(TransactionType::Deposit, 1, Some(Decimal::ONE), "2024-03-01T10:00:00Z"),
+            at(TransactionType::Deposit, 2, Some(Decimal::ONE), "2024-03-02T10:00:00Z"),
+            at(TransactionType::Dispute, 2, None, "2024-03-03T10:00:00Z"),
+            at(TransactionType::Deposit, 3, Some(Decimal::ONE), "2024-03-15T10:00:00Z"),
+        ]
+        .iter()
+        {
+            acc.process_with(tr, &rules, false).unwrap();
+        }
+        let mut ids: Vec<TransactionId> = acc.transaction_status.keys().copied().collect();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![2, 3], "disputed transactions are kept");
+
+        let resolve = at(TransactionType::Resolve, 2, None, "2024-03-16T10:00:00Z");
+        assert_eq!(acc.process_with(&resolve, &rules, false), Ok(()));
+        let deposit = at(TransactionType::Deposit, 4, Some(Decimal::ONE), "2024-03-20T10:00:00Z");
+        acc.process_with(&deposit, &rules, false).unwrap();
+        assert_eq!(acc.transaction_status.len(), 3, "pruning runs at most once per window");
+        let deposit = at(TransactionType::Deposit, 5, Some(Decimal::ONE), "2024-03-26T10:00:00Z");
+        acc.process_with(&deposit, &rules, false).unwrap();
+        let mut ids: Vec<TransactionId> = acc.transaction_status.keys().copied().collect();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![4, 5]);
+        assert_eq!(acc.available(), Decimal::new(5, 0));
+        assert!(acc.has_pruned());
+    }
+
+    #[test]
+    fn test_prune_with_huge_window() {
+        let rules = DisputeRules {
+            window: Some(Duration::days(u32::MAX.into())),
+            prune_expired: true,
+            ..DisputeRules::default()
+        };
+        let mut acc = Account::new(5);
+        let deposit = at(TransactionType::Deposit, 1, Some(Decimal::ONE), "2024-03-01T10:00:00Z");
+        assert_eq!(acc.process_with(&deposit, &rules, false), Ok(()));
+        let dispute = at(TransactionType::Dispute, 1, None, "2024-03-02T10:00:00Z");
+        assert_eq!(acc.process_with(&dispute, &rules, false), Ok(()));
+        assert_eq!(acc.held(), Decimal::ONE);
+    }
+
+    #[test]
+    fn test_close_requires_all_currencies_empty() {
+        let mut acc = Account::new(5);
+        let res = acc.process(&in_currency(TransactionType::Deposit, 1, Some(Decimal::ONE), Some("GBP")), false);
+        assert!(res.is_ok(), "deposit error: {:?}", res);
+
+        let close = Transaction {
+            reason: Some("CLOSE-1".to_string()),
+            ..in_currency(TransactionType::Close, 2, None, None)
+        };
+        assert_eq!(acc.process(&close, false), Err(Error::AccountNotEmpty));
+    }
+
+    fn output(client: ClientId, total: i64, locked: bool) -> AccountOutput {
+        AccountOutput {
+            client,
+            currency: DEFAULT_CURRENCY.to_string(),
+            available: Decimal::new(total, 0),
+            held: Decimal::ZERO,
+            total: Decimal::new(total, 0),
+            locked,
+            fees_paid: Decimal::ZERO,
+            credit_used: Decimal::ZERO,
+        }
+    }
+
+    #[test]
+    fn test_output_order() {
+        let accounts = vec![
+            output(3, 5, false),
+            output(1, 5, true),
+            output(4, 7, true),
+            output(2, 9, false),
+        ];
+        let clients = |order: OutputOrder| {
+            let mut sorted = accounts.clone();
+            order.sort(&mut sorted);
+            sorted.iter().map(|a| a.client).collect::<Vec<ClientId>>()
+        };
+
+        assert_eq!(clients(OutputOrder::Client), vec![1, 2, 3, 4]);
+        assert_eq!(clients(OutputOrder::TotalDesc), vec![2, 4, 1, 3]);
+        assert_eq!(clients(OutputOrder::LockedFirst), vec![1, 4, 2, 3]);
+    }
+
+    #[test]
+    fn test_round_output() {
+        assert_eq!(round_output(Decimal::new(123455, 5)).to_string(), "1.2346");
+        assert_eq!(round_output(Decimal::new(-123455, 5)).to_string(), "-1.2346");
+        assert_eq!(round_output(Decimal::new(15, 1)).to_string(), "1.5");
+        for zero in [Decimal::ZERO, Decimal::new(0, 1), Decimal::new(-4, 5)].iter() {
+            assert_eq!(round_output(*zero).to_string(), "0.0000", "{:?}", zero);
+        }
+    }
+
     #[test]
     fn test_failed_withdrawal_dispute() {
         let mut acc = Account::new(5);
@@ -607,6 +2073,10 @@ mod tests {
                 client_id: 5,
                 transaction_id: 1,
                 amount: Some(Decimal::new(123456, 2)),
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
@@ -617,11 +2087,15 @@ mod tests {
                 client_id: 5,
                 transaction_id: 2,
                 amount: Some(Decimal::new(999991111, 2)),
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
         assert_eq!(res, Err(Error::InsufficientFunds), "too large withdrawal should fail");
-        assert_eq!(acc.available, Decimal::new(123456, 2));
+        assert_eq!(acc.available(), Decimal::new(123456, 2));
         assert_eq!(acc.total(), Decimal::new(123456, 2));
         let res = acc.process(
             &Transaction {
@@ -629,13 +2103,17 @@ mod tests {
                 client_id: 5,
                 transaction_id: 2,
                 amount: None,
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
             },
             false,
         );
         assert_eq!(res, Err(Error::UnknownTransactionId), "failed withdrawal cannot be disputed");
 
-        assert_eq!(acc.held, Decimal::ZERO);
-        assert_eq!(acc.available, Decimal::new(123456, 2));
+        assert_eq!(acc.held(), Decimal::ZERO);
+        assert_eq!(acc.available(), Decimal::new(123456, 2));
         assert_eq!(acc.total(), Decimal::new(123456, 2));
         assert_eq!(acc.locked, false);
     }
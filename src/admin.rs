@@ -0,0 +1,146 @@
+use crate::journal::JournalEntry;
+use crate::transaction::*;
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::io;
+
+/// An accepted administrative transaction, kept apart from the customer transactions for auditing.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct AdminAction {
+    pub row: RowNumber,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+    pub amount: Option<Decimal>,
+    pub currency: Option<Currency>, // balance changed by an Adjustment
+    pub reason: Option<String>,
+    pub available_change: Decimal,
+    pub locked: bool,
+}
+
+impl From<&JournalEntry> for AdminAction {
+    fn from(entry: &JournalEntry) -> Self {
+        let tr = &entry.transaction;
+        AdminAction {
+            row: entry.row,
+            client: tr.client_id,
+            tx: tr.transaction_id,
+            transaction_type: tr.transaction_type.clone(),
+            amount: tr.amount,
+            currency: match tr.transaction_type {
+                TransactionType::Adjust => Some(tr.currency().to_string()),
+                _ => None,
+            },
+            reason: tr.reason.clone(),
+            available_change: entry.available_change.normalize(),
+            locked: entry.locked,
+        }
+    }
+}
+
+pub fn write_admin_actions(actions: &[AdminAction], output: &mut dyn io::Write) -> csv::Result<()> {
+    let mut writer = csv::Writer::from_writer(output);
+    for action in actions {
+        writer.serialize(action)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::PaymentEngine;
+
+    fn transaction(
+        transaction_type: TransactionType,
+        transaction_id: TransactionId,
+        amount: Option<Decimal>,
+        reason: Option<&str>,
+    ) -> Transaction {
+        Transaction {
+            transaction_type,
+            client_id: 3,
+            transaction_id,
+            amount,
+            reason: reason.map(String::from),
+            currency: None,
+            timestamp: None,
+            recipient: None,
+        }
+    }
+
+    #[test]
+    fn test_admin_actions_are_separated() {
+        let mut engine = PaymentEngine::default();
+        engine.collect_admin_actions();
+        let transactions = [
+            transaction(TransactionType::Deposit, 1, Some(Decimal::new(10, 0)), None),
+            transaction(TransactionType::Freeze, 2, None, Some("FRAUD-7")),
+            transaction(
+                TransactionType::Adjust,
+                3,
+                Some(Decimal::new(-25, 1)),
+                Some("FEE-REFUND"),
+            ),
+            transaction(TransactionType::Deposit, 4, Some(Decimal::new(10, 0)), None),
+            transaction(TransactionType::Unlock, 5, None, Some("FRAUD-7")),
+        ];
+        for tr in transactions.iter() {
+            let _ = engine.apply(tr);
+        }
+
+        let types: Vec<TransactionType> = engine
+            .admin_actions()
+            .iter()
+            .map(|a| a.transaction_type.clone())
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                TransactionType::Freeze,
+                TransactionType::Adjust,
+                TransactionType::Unlock
+            ]
+        );
+
+        let mut out = Vec::new();
+        write_admin_actions(engine.admin_actions(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "row,client,tx,type,amount,currency,reason,available_change,locked\n\
+             0,3,2,freeze,,,FRAUD-7,0,true\n\
+             0,3,3,adjust,-2.5,EUR,FEE-REFUND,-2.5,true\n\
+             0,3,5,unlock,,,FRAUD-7,0,false\n"
+        );
+
+        let mut uncollected = PaymentEngine::default();
+        for tr in transactions.iter() {
+            let _ = uncollected.apply(tr);
+        }
+        assert!(uncollected.admin_actions().is_empty(), "actions are only kept when asked for");
+        assert_eq!(uncollected.account(3), engine.account(3));
+    }
+
+    #[test]
+    fn test_reused_admin_id() {
+        let mut engine = PaymentEngine::default();
+        engine.collect_admin_actions();
+        let adjust = transaction(TransactionType::Adjust, 5, Some(Decimal::new(10, 0)), Some("X"));
+        assert_eq!(engine.apply(&adjust), Ok(()));
+        assert_eq!(engine.apply(&adjust), Err(Error::DuplicatedTransactionId));
+        assert_eq!(
+            engine.apply(&transaction(TransactionType::Deposit, 5, Some(Decimal::ONE), None)),
+            Err(Error::DuplicatedTransactionId)
+        );
+        assert_eq!(
+            engine.apply(&transaction(TransactionType::Freeze, 5, None, None)),
+            Err(Error::DuplicatedTransactionId)
+        );
+
+        assert_eq!(engine.account(3).map(|a| a.available()), Some(Decimal::new(10, 0)));
+        assert_eq!(engine.admin_actions().len(), 1);
+    }
+}
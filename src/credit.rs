@@ -0,0 +1,125 @@
+use crate::transaction::*;
+use crate::validation;
+
+use csv::{Position, ReaderBuilder, StringRecord, Trim};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+
+/// One row of the credit limit table: withdrawals of the client may take its available funds
+/// in the currency (EUR if missing) down to `-limit`.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+struct CreditLimitRow {
+    client: ClientId,
+    #[serde(default)]
+    currency: Option<Currency>,
+    limit: Decimal,
+}
+
+#[derive(Debug)]
+pub enum CreditError {
+    Csv(csv::Error),
+    InvalidCurrency { row: RowNumber },
+    NegativeLimit { row: RowNumber },
+    DuplicatedLimit { row: RowNumber },
+}
+
+impl fmt::Display for CreditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CreditError::Csv(e) => write!(f, "{}", e),
+            CreditError::InvalidCurrency { row } => write!(f, "invalid currency in row {}", row),
+            CreditError::NegativeLimit { row } => write!(f, "negative credit limit in row {}", row),
+            CreditError::DuplicatedLimit { row } => write!(f, "duplicated credit limit in row {}", row),
+        }
+    }
+}
+
+impl std::error::Error for CreditError {}
+
+impl From<csv::Error> for CreditError {
+    fn from(e: csv::Error) -> Self {
+        CreditError::Csv(e)
+    }
+}
+
+/// Credit limits of the clients per currency. Clients without a limit have no credit.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CreditLimits {
+    limits: HashMap<ClientId, BTreeMap<Currency, Decimal>>,
+}
+
+impl CreditLimits {
+    /// Limits of the client in every currency it has one.
+    pub fn of(&self, client: ClientId) -> BTreeMap<Currency, Decimal> {
+        self.limits.get(&client).cloned().unwrap_or_default()
+    }
+}
+
+pub fn read_limits(input: &mut dyn io::Read) -> std::result::Result<CreditLimits, CreditError> {
+    let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(input);
+    let mut table = CreditLimits::default();
+
+    let headers = reader.headers()?.clone();
+    let mut record = StringRecord::new();
+    while reader.read_record(&mut record)? {
+        let limit: CreditLimitRow = record.deserialize(Some(&headers))?;
+        let row = record.position().map_or(0, Position::line);
+        let mut currency = limit.currency.filter(|c| !c.is_empty());
+        validation::validate_currency(&mut currency).map_err(|_| CreditError::InvalidCurrency { row })?;
+        let currency = currency.unwrap_or_else(|| DEFAULT_CURRENCY.to_string());
+        if limit.limit < Decimal::ZERO {
+            return Err(CreditError::NegativeLimit { row });
+        }
+        let limits = table.limits.entry(limit.client).or_default();
+        if limits.insert(currency, limit.limit).is_some() {
+            return Err(CreditError::DuplicatedLimit { row });
+        }
+    }
+
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_limits() {
+        let csv = "client, currency, limit\n\
+                   1, , 100.0\n\
+                   1, usd, 50\n\
+                   2, EUR, 0.5";
+        let limits = read_limits(&mut csv.as_bytes()).expect("reading limits failed");
+        let expected: BTreeMap<Currency, Decimal> = vec![
+            ("EUR".to_string(), Decimal::new(100, 0)),
+            ("USD".to_string(), Decimal::new(50, 0)),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(limits.of(1), expected);
+        assert_eq!(limits.of(2).get("EUR"), Some(&Decimal::new(5, 1)));
+        assert!(limits.of(3).is_empty());
+
+        let csv = "client, limit\n1, 100.0";
+        let limits = read_limits(&mut csv.as_bytes()).expect("currency column is optional");
+        assert_eq!(limits.of(1).get(DEFAULT_CURRENCY), Some(&Decimal::new(100, 0)));
+    }
+
+    #[test]
+    fn test_invalid_limits() {
+        let res = read_limits(&mut "client, limit\n1, -1".as_bytes());
+        assert!(matches!(res, Err(CreditError::NegativeLimit { row: 2 })), "{:?}", res);
+
+        let res = read_limits(&mut "client, currency, limit\n1, EUR, 1\n1, , 2".as_bytes());
+        assert!(matches!(res, Err(CreditError::DuplicatedLimit { row: 3 })), "{:?}", res);
+
+        let res = read_limits(&mut "client, currency, limit\n1, EURO, 1".as_bytes());
+        assert!(matches!(res, Err(CreditError::InvalidCurrency { row: 2 })), "{:?}", res);
+
+        let res = read_limits(&mut "client, limit\none, 1".as_bytes());
+        assert!(matches!(res, Err(CreditError::Csv(_))), "{:?}", res);
+    }
+}
@@ -1,34 +1,112 @@
-use crate::account::{Account, AccountOutput};
-use crate::transaction::{ClientId, Transaction};
+use crate::account::AccountOutput;
+use crate::transaction::{Row, RowNumber, Transaction};
 
 use csv::*;
-use std::collections::HashMap;
+use serde::Serialize;
 use std::io;
 
-pub fn read_transactions(input: &mut dyn io::Read, verbose: bool) -> Result<Vec<Transaction>> {
-    let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(input);
+pub fn read_transactions(input: &mut dyn io::Read, verbose: bool) -> Result<Vec<Row>> {
+    stream_transactions(input, verbose).collect()
+}
+
+/// Lazily deserializes the input one row at a time, so the whole file never has to be held in memory.
+/// Rows may leave out trailing optional columns, like the reason of customer transactions.
+pub fn stream_transactions(input: &mut dyn io::Read, verbose: bool) -> TransactionRows<&mut dyn io::Read> {
+    TransactionRows {
+        reader: ReaderBuilder::new()
+            .trim(Trim::All)
+            .flexible(true)
+            .from_reader(input),
+        headers: None,
+        record: StringRecord::new(),
+        verbose,
+        done: false,
+    }
+}
+
+/// Iterator over the transactions of a CSV input, tagged with their line numbers.
+pub struct TransactionRows<R> {
+    reader: Reader<R>,
+    headers: Option<StringRecord>,
+    record: StringRecord,
+    verbose: bool,
+    done: bool, // set when the header cannot be read, since no row can be parsed without it
+}
 
-    let mut res = Vec::with_capacity(100);
+impl<R: io::Read> Iterator for TransactionRows<R> {
+    type Item = Result<Row>;
 
-    for row in reader.deserialize() {
-        let tr: Transaction = row?;
-        if verbose {
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if self.headers.is_none() {
+            match self.reader.headers() {
+                Ok(headers) => self.headers = Some(headers.clone()),
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        match self.reader.read_record(&mut self.record) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => return Some(Err(e)),
+        }
+
+        let line = self.record.position().map_or(0, Position::line);
+        let row = self.record.deserialize::<Transaction>(self.headers.as_ref());
+        if let (true, Ok(tr)) = (self.verbose, &row) {
             println!("{}: {:?}", tr.transaction_id, tr);
         }
-        res.push(tr);
+        Some(row.map(|tr| (line, tr)))
     }
-    Ok(res)
 }
 
-pub fn write_accounts(
-    accounts: HashMap<ClientId, Account>,
-    output: &mut dyn io::Write,
-) -> Result<()> {
-    let acc_list: Vec<&Account> = accounts.values().collect();
-    let out_list: Vec<AccountOutput> = acc_list.iter().map(|a| (*a).into()).collect();
+/// A row which could not be parsed, with the reason of the failure.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct MalformedRow {
+    pub row: RowNumber,
+    pub error: String,
+}
 
+/// Skips the rows which cannot be parsed, collecting them into `malformed`.
+/// Errors which are not tied to a transaction row, like I/O errors or an invalid header, still stop the input.
+pub fn skip_malformed<'a, I>(rows: I, malformed: &'a mut Vec<MalformedRow>) -> impl Iterator<Item = Result<Row>> + 'a
+where
+    I: IntoIterator<Item = Result<Row>>,
+    I::IntoIter: 'a,
+{
+    rows.into_iter().filter_map(move |row| match row {
+        Err(e) if !e.is_io_error() => match e.position() {
+            // record 0 is the header
+            Some(pos) if pos.record() > 0 => {
+                malformed.push(MalformedRow {
+                    row: pos.line(),
+                    error: e.to_string(),
+                });
+                None
+            }
+            _ => Some(Err(e)),
+        },
+        row => Some(row),
+    })
+}
+
+pub fn write_malformed_rows(rows: &[MalformedRow], output: &mut dyn io::Write) -> Result<()> {
     let mut writer = csv::Writer::from_writer(output);
-    for out in out_list {
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn write_accounts(accounts: &[AccountOutput], output: &mut dyn io::Write) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(output);
+    for out in accounts {
         writer.serialize(out)?;
     }
     writer.flush()?;
@@ -38,7 +116,7 @@ pub fn write_accounts(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::transaction::{Transaction, TransactionType};
+    use crate::transaction::{RowNumber, Transaction, TransactionType};
     use rust_decimal::Decimal;
 
     #[test]
@@ -48,12 +126,19 @@ mod tests {
         assert!(res.is_ok(), "csv parsing error: {:?}", res);
 
         if let Ok(transactions) = res {
-            let expected = vec![Transaction {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 5,
-                amount: Some(Decimal::new(987654321, 4)),
-            }];
+            let expected = vec![(
+                2,
+                Transaction {
+                    transaction_type: TransactionType::Deposit,
+                    client_id: 1,
+                    transaction_id: 5,
+                    amount: Some(Decimal::new(987654321, 4)),
+                    reason: None,
+                    currency: None,
+                    timestamp: None,
+                    recipient: None,
+                },
+            )];
 
             assert_eq!(transactions, expected)
         }
@@ -66,15 +151,102 @@ mod tests {
         assert!(res.is_ok(), "csv parsing error: {:?}", res);
 
         if let Ok(transactions) = res {
-            let expected = vec![Transaction {
-                transaction_type: TransactionType::Dispute,
-                client_id: 1,
-                transaction_id: 5,
-                amount: None,
-            }];
+            let expected = vec![(
+                2,
+                Transaction {
+                    transaction_type: TransactionType::Dispute,
+                    client_id: 1,
+                    transaction_id: 5,
+                    amount: None,
+                    reason: None,
+                    currency: None,
+                    timestamp: None,
+                    recipient: None,
+                },
+            )];
 
             assert_eq!(transactions, expected)
         }
     }
 
+    #[test]
+    fn test_stream_stops_at_error() {
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 1.0\nfoo, 1, 2, 1.0\ndeposit, 1, 3, 1.0";
+        let mut bytes = input.as_bytes();
+        let mut rows = stream_transactions(&mut bytes, false);
+
+        assert!(matches!(rows.next(), Some(Ok(_))), "first row should be parsed");
+        assert!(matches!(rows.next(), Some(Err(_))), "unknown type should fail");
+    }
+
+    #[test]
+    fn test_row_numbers() {
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, 1.0";
+        let res = read_transactions(&mut input.as_bytes(), false).expect("csv parsing error");
+
+        let rows: Vec<RowNumber> = res.iter().map(|(row, _)| *row).collect();
+        assert_eq!(rows, vec![2, 3], "row numbers should be line numbers of the input");
+    }
+
+    #[test]
+    fn test_read_reason() {
+        let input = "type, client, tx, amount, reason\ndeposit, 1, 1, 1.0\nadjust, 1, 2, -0.5, SUPPORT-12";
+        let res = read_transactions(&mut input.as_bytes(), false).expect("csv parsing error");
+
+        assert_eq!(res[0].1.reason, None, "reason column is optional");
+        assert_eq!(res[1].1.transaction_type, TransactionType::Adjust);
+        assert_eq!(res[1].1.reason.as_deref(), Some("SUPPORT-12"));
+    }
+
+    #[test]
+    fn test_read_timestamp() {
+        let input = "type, client, tx, amount, timestamp\ndeposit, 1, 1, 1.0, 2024-03-01T10:00:00Z\ndeposit, 1, 2, 1.0,";
+        let res = read_transactions(&mut input.as_bytes(), false).expect("csv parsing error");
+
+        assert_eq!(
+            res[0].1.timestamp.map(|t| t.to_rfc3339()).as_deref(),
+            Some("2024-03-01T10:00:00+00:00")
+        );
+        assert_eq!(res[1].1.timestamp, None, "timestamp is optional");
+    }
+
+    #[test]
+    fn test_skip_malformed() {
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 1.0\nbonus, 1, 2, 1.0\ndeposit, 1, 3, abc\ndeposit, 70000, 4, 1.0\ndeposit, 1, 5, 1.0";
+        let mut bytes = input.as_bytes();
+        let mut malformed = Vec::new();
+        let rows: Vec<Row> = skip_malformed(stream_transactions(&mut bytes, false), &mut malformed)
+            .collect::<Result<_>>()
+            .expect("malformed rows should be skipped");
+
+        let parsed: Vec<RowNumber> = rows.iter().map(|(row, _)| *row).collect();
+        assert_eq!(parsed, vec![2, 6]);
+        let skipped: Vec<RowNumber> = malformed.iter().map(|m| m.row).collect();
+        assert_eq!(skipped, vec![3, 4, 5]);
+        assert!(malformed[0].error.contains("bonus"), "error: {}", malformed[0].error);
+    }
+
+    #[test]
+    fn test_skip_malformed_invalid_utf8() {
+        let mut input = b"type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, \xff\n".to_vec();
+        input.extend_from_slice(b"deposit, 1, 3, 1.0");
+        let mut bytes = input.as_slice();
+        let mut malformed = Vec::new();
+        let rows: Vec<Row> = skip_malformed(stream_transactions(&mut bytes, false), &mut malformed)
+            .collect::<Result<_>>()
+            .expect("malformed rows should be skipped");
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(malformed.len(), 1);
+        assert_eq!(malformed[0].row, 3);
+    }
+
+    #[test]
+    fn test_invalid_header_stops() {
+        let input = b"type, \xff, tx, amount\ndeposit, 1, 1, 1.0";
+        let mut bytes = &input[..];
+        let mut malformed = Vec::new();
+        let res: Result<Vec<Row>> = skip_malformed(stream_transactions(&mut bytes, false), &mut malformed).collect();
+        assert!(res.is_err(), "header error should not be skipped");
+    }
 }
@@ -0,0 +1,1107 @@
+use crate::account::{Account, AccountOutput, DisputeRules, OutputOrder};
+use crate::admin::AdminAction;
+use crate::credit::CreditLimits;
+use crate::fees::{Fee, FeeItem, FeeSchedule};
+use crate::journal::{Journal, JournalEntry};
+use crate::registry::{DuplicatePolicy, DuplicateWarning, TransactionRegistry};
+use crate::rejection::{reject, Rejection};
+use crate::state::EngineState;
+use crate::transaction::*;
+use crate::validation::{self, AmountRules};
+
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// How the engine treats the timestamps of the transactions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Chronology {
+    /// Input order is chronological, timestamps are ignored.
+    Trust,
+    /// Rejects a transaction older than an already accepted one of the same client.
+    Check,
+    /// Orders a batch by timestamp before processing, rows without timestamp are rejected.
+    /// A stream cannot be reordered, so it is only checked.
+    Sort,
+}
+
+impl Chronology {
+    pub const VALUES: [&'static str; 3] = ["trust", "check", "sort"];
+}
+
+impl FromStr for Chronology {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "trust" => Ok(Chronology::Trust),
+            "check" => Ok(Chronology::Check),
+            "sort" => Ok(Chronology::Sort),
+            _ => Err(format!("unknown chronology: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EngineConfig {
+    pub duplicates: DuplicatePolicy,
+    pub disputes: DisputeRules,
+    pub amounts: AmountRules,
+    pub chronology: Chronology,
+    pub fees: FeeSchedule,
+    pub credit_limits: CreditLimits,
+    pub verbose: bool,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            duplicates: DuplicatePolicy::Reject,
+            disputes: DisputeRules::default(),
+            amounts: AmountRules::default(),
+            chronology: Chronology::Trust,
+            fees: FeeSchedule::default(),
+            credit_limits: CreditLimits::default(),
+            verbose: false,
+        }
+    }
+}
+
+/// Keeps the accounts of all clients and routes every transaction to the account of its client.
+#[derive(Debug)]
+pub struct PaymentEngine {
+    config: EngineConfig,
+    registry: TransactionRegistry,
+    accounts: HashMap<ClientId, Account>,
+    journal: Option<Journal>,
+    admin_actions: Option<Vec<AdminAction>>, // only kept for the admin log
+    fee_items: Option<Vec<FeeItem>>,         // only kept for the fee log
+    warnings: Vec<DuplicateWarning>,
+}
+
+impl PaymentEngine {
+    pub fn new(config: EngineConfig) -> PaymentEngine {
+        PaymentEngine {
+            registry: TransactionRegistry::new(config.duplicates),
+            config,
+            accounts: HashMap::new(),
+            journal: None,
+            admin_actions: None,
+            fee_items: None,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Continues from the state saved at the end of a previous run.
+    /// The credit limits are not saved, they come from the config.
+    pub fn from_state(config: EngineConfig, state: EngineState) -> PaymentEngine {
+        PaymentEngine {
+            registry: TransactionRegistry::with_owners(
+                config.duplicates,
+                state.transaction_owners,
+                state.shared_transaction_ids,
+            ),
+            accounts: state
+                .accounts
+                .into_iter()
+                .map(|mut acc| {
+                    acc.set_credit_limits(config.credit_limits.of(acc.client_id()));
+                    (acc.client_id(), acc)
+                })
+                .collect(),
+            config,
+            journal: None,
+            admin_actions: None,
+            fee_items: None,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Every accepted transaction will be recorded in the journal from now on.
+    pub fn set_journal(&mut self, journal: Journal) {
+        self.journal = Some(journal);
+    }
+
+    pub fn take_journal(&mut self) -> Option<Journal> {
+        self.journal.take()
+    }
+
+    /// Every accepted administrative transaction will be kept from now on.
+    pub fn collect_admin_actions(&mut self) {
+        self.admin_actions.get_or_insert_with(Vec::new);
+    }
+
+    /// Every charged fee will be kept as a line item from now on.
+    pub fn collect_fee_items(&mut self) {
+        self.fee_items.get_or_insert_with(Vec::new);
+    }
+
+    /// Full state of the engine, accounts ordered by client ID.
+    pub fn state(&self) -> EngineState {
+        let mut accounts: Vec<Account> = self.accounts.values().cloned().collect();
+        accounts.sort_by_key(Account::client_id);
+
+        EngineState {
+            accounts,
+            transaction_owners: self.registry.owners().clone(),
+            shared_transaction_ids: self.registry.shared().clone(),
+        }
+    }
+
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+
+    /// Applies a single transaction, creating the account of the client if it doesn't exist yet.
+    /// Transactions applied this way are journaled with row number 0.
+    pub fn apply(&mut self, tr: &Transaction) -> Result<()> {
+        self.apply_row(0, tr).map(|_| ())
+    }
+
+    pub(crate) fn apply_row(&mut self, row: RowNumber, tr: &Transaction) -> Result<JournalEntry> {
+        let mut tr = tr.clone();
+        validate(&mut tr, &self.config)?;
+        if let Some(warning) = self.registry.claim(row, &tr)? {
+            self.warnings.push(warning);
+        }
+        let entry = if tr.transaction_type == TransactionType::Transfer {
+            self.transfer(row, &tr)?
+        } else {
+            let config = &self.config;
+            let acc = self
+                .accounts
+                .entry(tr.client_id)
+                .or_insert_with(|| open_account(tr.client_id, config));
+            process_journaled(acc, row, &tr, config, &self.registry)?
+        };
+        if let Some(journal) = self.journal.as_mut() {
+            journal.record(&entry);
+        }
+        if tr.transaction_type.is_admin() {
+            if let Some(admin_actions) = self.admin_actions.as_mut() {
+                admin_actions.push(AdminAction::from(&entry));
+            }
+        }
+        if let Some(fee_items) = self.fee_items.as_mut() {
+            fee_items.extend(FeeItem::from_entry(&entry));
+        }
+        Ok(entry)
+    }
+
+    /// Applies the rows one at a time as they arrive, without collecting the input first.
+    /// Memory usage is bounded by the number of clients and their transaction history, not by the number of rows.
+    /// Stops at the first input error.
+    pub fn apply_stream<I, E>(&mut self, rows: I) -> std::result::Result<Vec<Rejection>, E>
+    where
+        I: IntoIterator<Item = std::result::Result<Row, E>>,
+    {
+        let mut rejections = Vec::new();
+
+        for row in rows {
+            let (row, tr) = row?;
+            if let Err(e) = self.apply_row(row, &tr) {
+                reject(&mut rejections, row, &tr, e, self.config.verbose);
+            }
+        }
+
+        Ok(rejections)
+    }
+
+    /// Applies a batch of rows, processing the clients in parallel.
+    /// Transfers are applied one at a time, after every row before them and before every row after them.
+    /// Rejections are returned in input order.
+    pub fn apply_batch(&mut self, rows: Vec<Row>) -> Vec<Rejection> {
+        let journaling = self.journal.is_some();
+        let logging_fees = self.fee_items.is_some();
+        let mut results = BatchResults::default();
+
+        for stage in self.dispatch(rows, &mut results.rejections) {
+            match stage {
+                Stage::Clients(queues) => self.process_queues(queues, &mut results),
+                Stage::Transfer((row, tr)) => match self.transfer(row, &tr) {
+                    Ok(entry) => {
+                        if logging_fees {
+                            results.fee_items.extend(FeeItem::from_entry(&entry));
+                        }
+                        if journaling {
+                            results.entries.push(entry);
+                        }
+                    }
+                    Err(e) => reject(&mut results.rejections, row, &tr, e, self.config.verbose),
+                },
+            }
+        }
+
+        let BatchResults {
+            mut rejections,
+            mut entries,
+            mut admin_actions,
+            mut fee_items,
+        } = results;
+
+        if let Some(collected) = self.admin_actions.as_mut() {
+            admin_actions.sort_by_key(|a| a.row);
+            collected.extend(admin_actions);
+        }
+        if let Some(collected) = self.fee_items.as_mut() {
+            fee_items.sort_by_key(|f| f.row);
+            collected.extend(fee_items);
+        }
+
+        if let Some(journal) = self.journal.as_mut() {
+            if self.config.chronology == Chronology::Sort {
+                entries.sort_by_key(|e| (e.transaction.timestamp, e.row));
+            } else {
+                entries.sort_by_key(|e| e.row);
+            }
+            for entry in &entries {
+                journal.record(entry);
+            }
+        }
+
+        rejections.sort_by_key(|r| r.row);
+        rejections
+    }
+
+    /// Processes the queues of the clients in parallel.
+    fn process_queues(&mut self, queues: HashMap<ClientId, Vec<Row>>, results: &mut BatchResults) {
+        let journaling = self.journal.is_some();
+        let logging_admin = self.admin_actions.is_some();
+        let logging_fees = self.fee_items.is_some();
+        let work: Vec<(Account, Vec<Row>)> = queues
+            .into_iter()
+            .map(|(cid, rows)| {
+                let acc = self
+                    .accounts
+                    .remove(&cid)
+                    .unwrap_or_else(|| open_account(cid, &self.config));
+                (acc, rows)
+            })
+            .collect();
+
+        // using rayon to process clients in parallel
+        let config = &self.config;
+        let registry = &self.registry;
+        let processed: Vec<ClientBatch> = work
+            .into_par_iter()
+            .map(|(mut account, rows)| {
+                let mut rejections = Vec::new();
+                let mut entries = Vec::new();
+                let mut admin_actions = Vec::new();
+                let mut fee_items = Vec::new();
+                for (row, tr) in &rows {
+                    match process_journaled(&mut account, *row, tr, config, registry) {
+                        Ok(entry) => {
+                            if logging_admin && tr.transaction_type.is_admin() {
+                                admin_actions.push(AdminAction::from(&entry));
+                            }
+                            if logging_fees {
+                                fee_items.extend(FeeItem::from_entry(&entry));
+                            }
+                            if journaling {
+                                entries.push(entry);
+                            }
+                        }
+                        Err(e) => reject(&mut rejections, *row, tr, e, config.verbose),
+                    }
+                }
+                ClientBatch {
+                    account,
+                    rejections,
+                    entries,
+                    admin_actions,
+                    fee_items,
+                }
+            })
+            .collect();
+
+        for batch in processed {
+            self.accounts.insert(batch.account.client_id(), batch.account);
+            results.rejections.extend(batch.rejections);
+            results.entries.extend(batch.entries);
+            results.admin_actions.extend(batch.admin_actions);
+            results.fee_items.extend(batch.fee_items);
+        }
+    }
+
+    /// Moves the funds of a transfer between the two accounts, changing neither of them if it fails.
+    /// The journal entry describes the change of the sender, with the credit of the recipient in `received`.
+    fn transfer(&mut self, row: RowNumber, tr: &Transaction) -> Result<JournalEntry> {
+        let to = tr.recipient.ok_or(Error::InvalidRecipient)?;
+        let existed = self.accounts.contains_key(&to);
+        let mut recipient = self
+            .accounts
+            .remove(&to)
+            .unwrap_or_else(|| open_account(to, &self.config));
+        let config = &self.config;
+        let registry = &self.registry;
+        let sender = self
+            .accounts
+            .entry(tr.client_id)
+            .or_insert_with(|| open_account(tr.client_id, config));
+
+        let res = recipient.prepare_receive(tr).and_then(|balance| {
+            let mut entry = process_journaled(sender, row, tr, config, registry)?;
+            entry.received = Some(balance.available - recipient.balance(tr.currency()).available);
+            recipient.commit_receive(tr, balance);
+            Ok(entry)
+        });
+        // a failed transfer doesn't open an account for the recipient
+        if existed || res.is_ok() {
+            self.accounts.insert(to, recipient);
+        }
+        res
+    }
+
+    /// Splits the input into one queue per client, keeping the original order within each queue.
+    /// Rows of different clients may be interleaved arbitrarily, every client still gets exactly one queue
+    /// between two transfers; a transfer is a stage of its own, since it changes two accounts.
+    /// Amounts are validated and transaction IDs are claimed here, in input order, before any parallel processing starts.
+    /// When sorting by time, the input order is the order of the timestamps, ties keep the row order.
+    fn dispatch(&mut self, mut rows: Vec<Row>, rejections: &mut Vec<Rejection>) -> Vec<Stage> {
+        if self.config.chronology == Chronology::Sort {
+            let verbose = self.config.verbose;
+            rows.retain(|(row, tr)| {
+                if tr.timestamp.is_none() {
+                    reject(rejections, *row, tr, Error::MissingTimestamp, verbose);
+                }
+                tr.timestamp.is_some()
+            });
+            rows.sort_by_key(|(_, tr)| tr.timestamp);
+        }
+
+        let mut stages = Vec::new();
+        let mut queues: HashMap<ClientId, Vec<Row>> = HashMap::new();
+        for (row, mut tr) in rows {
+            let claim = validate(&mut tr, &self.config).and_then(|_| self.registry.claim(row, &tr));
+            match claim {
+                Ok(warning) => {
+                    self.warnings.extend(warning);
+                    if tr.transaction_type == TransactionType::Transfer {
+                        if !queues.is_empty() {
+                            stages.push(Stage::Clients(std::mem::take(&mut queues)));
+                        }
+                        stages.push(Stage::Transfer((row, tr)));
+                    } else {
+                        queues.entry(tr.client_id).or_default().push((row, tr));
+                    }
+                }
+                Err(e) => reject(rejections, row, &tr, e, self.config.verbose),
+            }
+        }
+        if !queues.is_empty() {
+            stages.push(Stage::Clients(queues));
+        }
+        stages
+    }
+
+    /// Administrative transactions accepted by this engine since `collect_admin_actions`, in input order.
+    pub fn admin_actions(&self) -> &[AdminAction] {
+        self.admin_actions.as_deref().unwrap_or_default()
+    }
+
+    /// Transaction IDs let through by the Warn duplicate policy, in input order.
+    pub fn warnings(&self) -> &[DuplicateWarning] {
+        &self.warnings
+    }
+
+    /// Fees charged by this engine since `collect_fee_items`, in input order.
+    pub fn fee_items(&self) -> &[FeeItem] {
+        self.fee_items.as_deref().unwrap_or_default()
+    }
+
+    pub fn account(&self, client_id: ClientId) -> Option<&Account> {
+        self.accounts.get(&client_id)
+    }
+
+    pub fn accounts(&self) -> impl Iterator<Item = &Account> {
+        self.accounts.values()
+    }
+
+    /// Current balances of all accounts, in the same form as the CSV output, ordered by client ID and currency.
+    pub fn snapshot(&self) -> Vec<AccountOutput> {
+        self.snapshot_ordered(OutputOrder::Client)
+    }
+
+    pub fn snapshot_ordered(&self, order: OutputOrder) -> Vec<AccountOutput> {
+        let mut accounts: Vec<AccountOutput> = self
+            .accounts()
+            .flat_map(AccountOutput::from_account)
+            .collect();
+        order.sort(&mut accounts);
+        accounts
+    }
+}
+
+/// Part of a batch which is processed at once.
+enum Stage {
+    Clients(HashMap<ClientId, Vec<Row>>),
+    Transfer(Row),
+}
+
+/// Everything collected while processing a batch, besides the accounts.
+#[derive(Default)]
+struct BatchResults {
+    rejections: Vec<Rejection>,
+    entries: Vec<JournalEntry>,
+    admin_actions: Vec<AdminAction>,
+    fee_items: Vec<FeeItem>,
+}
+
+/// Result of processing the queue of one client in a batch.
+struct ClientBatch {
+    account: Account,
+    rejections: Vec<Rejection>,
+    entries: Vec<JournalEntry>,
+    admin_actions: Vec<AdminAction>,
+    fee_items: Vec<FeeItem>,
+}
+
+/// New account of the client with its credit limits.
+fn open_account(client: ClientId, config: &EngineConfig) -> Account {
+    let mut acc = Account::new(client);
+    acc.set_credit_limits(config.credit_limits.of(client));
+    acc
+}
+
+/// Checks the input fields of the transaction, normalizing them where the configuration allows.
+fn validate(tr: &mut Transaction, config: &EngineConfig) -> Result<()> {
+    config.amounts.validate(tr)?;
+    validation::validate_currency(&mut tr.currency)?;
+    validation::validate_recipient(tr)
+}
+
+/// Processes the transaction and describes the state change it caused.
+/// A transaction forgotten by the account is still in the registry, so referring to it is past the dispute window.
+fn process_journaled(
+    acc: &mut Account,
+    row: RowNumber,
+    tr: &Transaction,
+    config: &EngineConfig,
+    registry: &TransactionRegistry,
+) -> Result<JournalEntry> {
+    if config.chronology != Chronology::Trust {
+        acc.check_order(tr)?;
+    }
+    let currency = acc.transaction_currency(tr).to_string();
+    let fee = config
+        .fees
+        .fee(&tr.transaction_type, acc.fee_base(tr), config.amounts.scale)?;
+    let before = acc.balance(&currency);
+    let res = acc.process_charged(tr, &config.disputes, fee, config.verbose);
+    if res == Err(Error::UnknownTransactionId) && acc.has_pruned() && registry.is_used_by(tr.transaction_id, tr.client_id) {
+        return Err(Error::DisputeWindowExpired);
+    }
+    res?;
+    let after = acc.balance(&currency);
+
+    Ok(JournalEntry {
+        row,
+        transaction: tr.clone(),
+        available_change: after.available - before.available,
+        held_change: after.held - before.held,
+        locked: acc.is_locked(),
+        fee: (!fee.is_zero()).then_some(Fee {
+            currency,
+            amount: fee,
+        }),
+        received: None,
+    })
+}
+
+impl Default for PaymentEngine {
+    fn default() -> Self {
+        PaymentEngine::new(EngineConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv_handler;
+    use rust_decimal::Decimal;
+
+    fn row(
+        row: RowNumber,
+        transaction_type: TransactionType,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        amount: Option<Decimal>,
+    ) -> Row {
+        (
+            row,
+            Transaction {
+                transaction_type,
+                client_id,
+                transaction_id,
+                amount,
+                reason: None,
+                currency: None,
+                timestamp: None,
+                recipient: None,
+            },
+        )
+    }
+
+    fn batch_fixture(csv: &str) -> (PaymentEngine, Vec<Rejection>) {
+        let rows = csv_handler::read_transactions(&mut csv.as_bytes(), false)
+            .expect("fixture parsing failed");
+        let mut engine = PaymentEngine::default();
+        let rejections = engine.apply_batch(rows);
+        (engine, rejections)
+    }
+
+    fn stream_fixture(csv: &str) -> (PaymentEngine, Vec<Rejection>) {
+        let mut bytes = csv.as_bytes();
+        let mut engine = PaymentEngine::default();
+        let rejections = engine
+            .apply_stream(csv_handler::stream_transactions(&mut bytes, false))
+            .expect("fixture parsing failed");
+        (engine, rejections)
+    }
+
+    fn balances(engine: &PaymentEngine) -> HashMap<ClientId, (Decimal, Decimal, bool)> {
+        engine
+            .accounts()
+            .map(|a| (a.client_id(), (a.available(), a.held(), a.is_locked())))
+            .collect()
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut engine = PaymentEngine::default();
+        let (_, deposit) = row(
+            2,
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(Decimal::new(100, 0)),
+        );
+        let (_, withdrawal) = row(
+            3,
+            TransactionType::Withdrawal,
+            1,
+            2,
+            Some(Decimal::new(130, 0)),
+        );
+
+        assert_eq!(engine.apply(&deposit), Ok(()));
+        assert_eq!(engine.apply(&withdrawal), Err(Error::InsufficientFunds));
+        assert_eq!(
+            engine.account(1).map(Account::available),
+            Some(Decimal::new(100, 0))
+        );
+        assert_eq!(engine.account(2), None);
+    }
+
+    #[test]
+    fn test_apply_stream() {
+        let rows: Vec<std::result::Result<Row, ()>> = vec![
+            Ok(row(
+                2,
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Decimal::new(100, 0)),
+            )),
+            Ok(row(
+                3,
+                TransactionType::Deposit,
+                2,
+                2,
+                Some(Decimal::new(50, 0)),
+            )),
+            Ok(row(
+                4,
+                TransactionType::Withdrawal,
+                1,
+                3,
+                Some(Decimal::new(30, 0)),
+            )),
+        ];
+        let mut engine = PaymentEngine::default();
+        let rejections = engine.apply_stream(rows).expect("stream processing failed");
+
+        assert_eq!(engine.accounts().count(), 2);
+        assert_eq!(
+            engine.account(1).map(Account::available),
+            Some(Decimal::new(70, 0))
+        );
+        assert_eq!(
+            engine.account(2).map(Account::available),
+            Some(Decimal::new(50, 0))
+        );
+        assert!(rejections.is_empty());
+    }
+
+    #[test]
+    fn test_apply_stream_error() {
+        let rows = vec![
+            Ok(row(
+                2,
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Decimal::new(100, 0)),
+            )),
+            Err("bad row"),
+        ];
+        let res = PaymentEngine::default().apply_stream(rows);
+        assert_eq!(res, Err("bad row"));
+    }
+
+    #[test]
+    fn test_apply_batch_interleaved() {
+        let (engine, rejections) = batch_fixture(include_str!("../test/example_1.csv"));
+
+        let acc = balances(&engine);
+        assert_eq!(acc.len(), 2);
+        assert_eq!(acc[&1], (Decimal::new(15, 1), Decimal::ZERO, false));
+        assert_eq!(acc[&2], (Decimal::new(2, 0), Decimal::ZERO, false));
+
+        let rejected: Vec<(RowNumber, Error)> =
+            rejections.into_iter().map(|r| (r.row, r.error)).collect();
+        assert_eq!(rejected, vec![(6, Error::InsufficientFunds)]);
+    }
+
+    #[test]
+    fn test_apply_batch_interleaved_disputes() {
+        let (engine, rejections) = batch_fixture(include_str!("../test/example_2_all_types.csv"));
+
+        let acc = balances(&engine);
+        assert_eq!(acc.len(), 2);
+        assert_eq!(acc[&1], (Decimal::new(15, 1), Decimal::ZERO, false));
+        assert_eq!(acc[&2], (Decimal::new(-1, 0), Decimal::ZERO, true));
+        assert!(rejections.is_empty());
+    }
+
+    #[test]
+    fn test_apply_batch_matches_stream() {
+        let fixtures = [
+            include_str!("../test/example_1.csv"),
+            include_str!("../test/example_2_all_types.csv"),
+            include_str!("../test/example_3_large.csv"),
+            include_str!("../test/example_4_large_many_client.csv"),
+            include_str!("../test/example_5_multi_currency.csv"),
+            include_str!("../test/example_6_refunds.csv"),
+            include_str!("../test/example_7_transfers.csv"),
+        ];
+        for csv in fixtures.iter() {
+            let (batch, batch_rejections) = batch_fixture(csv);
+            let (stream, stream_rejections) = stream_fixture(csv);
+            assert_eq!(balances(&batch), balances(&stream));
+            assert_eq!(batch_rejections, stream_rejections);
+        }
+    }
+
+    #[test]
+    fn test_apply_batch_continues_existing_accounts() {
+        let mut engine = PaymentEngine::default();
+        engine.apply_batch(vec![row(
+            2,
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(Decimal::new(100, 0)),
+        )]);
+        let rejections = engine.apply_batch(vec![
+            row(
+                2,
+                TransactionType::Withdrawal,
+                1,
+                2,
+                Some(Decimal::new(30, 0)),
+            ),
+            row(3, TransactionType::Dispute, 1, 1, None),
+        ]);
+
+        assert!(rejections.is_empty());
+        assert_eq!(
+            balances(&engine)[&1],
+            (Decimal::new(-30, 0), Decimal::new(100, 0), false)
+        );
+    }
+
+    #[test]
+    fn test_duplicate_across_clients() {
+        let csv =
+            "type, client, tx, amount\ndeposit, 1, 1, 5.0\ndeposit, 2, 1, 7.0\ndeposit, 2, 2, 3.0";
+
+        let (engine, rejections) = batch_fixture(csv);
+        assert_eq!(
+            engine.account(1).map(Account::available),
+            Some(Decimal::new(5, 0))
+        );
+        assert_eq!(
+            engine.account(2).map(Account::available),
+            Some(Decimal::new(3, 0)),
+            "duplicated ID should be rejected"
+        );
+        assert_eq!(
+            rejections,
+            vec![Rejection {
+                row: 3,
+                client: 2,
+                tx: 1,
+                transaction_type: TransactionType::Deposit,
+                error: Error::DuplicatedTransactionId,
+            }]
+        );
+
+        let rows = csv_handler::read_transactions(&mut csv.as_bytes(), false).unwrap();
+        let mut engine = PaymentEngine::new(EngineConfig {
+            duplicates: DuplicatePolicy::Allow,
+            ..EngineConfig::default()
+        });
+        engine.apply_batch(rows);
+        assert_eq!(
+            engine.account(2).map(Account::available),
+            Some(Decimal::new(10, 0)),
+            "duplicated ID should be allowed"
+        );
+        assert!(engine.warnings().is_empty());
+
+        let config = EngineConfig {
+            duplicates: DuplicatePolicy::Warn,
+            ..EngineConfig::default()
+        };
+        let warning = DuplicateWarning {
+            row: 3,
+            client: 2,
+            tx: 1,
+            owner: 1,
+        };
+        let rows = csv_handler::read_transactions(&mut csv.as_bytes(), false).unwrap();
+        let mut engine = PaymentEngine::new(config.clone());
+        assert!(engine.apply_batch(rows).is_empty(), "warnings are not rejections");
+        assert_eq!(engine.warnings(), std::slice::from_ref(&warning));
+
+        let mut bytes = csv.as_bytes();
+        let mut engine = PaymentEngine::new(config);
+        engine
+            .apply_stream(csv_handler::stream_transactions(&mut bytes, false))
+            .unwrap();
+        assert_eq!(engine.warnings(), &[warning]);
+    }
+
+    const UNORDERED: &str = "type, client, tx, amount, timestamp\n\
+                             deposit, 1, 1, 5.0, 2024-03-01T10:00:00Z\n\
+                             withdrawal, 1, 2, 2.0, 2024-03-01T12:00:00Z\n\
+                             deposit, 1, 3, 3.0, 2024-03-01T11:00:00Z\n\
+                             deposit, 2, 4, 1.0,";
+
+    fn with_chronology(chronology: Chronology) -> PaymentEngine {
+        PaymentEngine::new(EngineConfig {
+            chronology,
+            ..EngineConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_chronology_check() {
+        let rows = csv_handler::read_transactions(&mut UNORDERED.as_bytes(), false).unwrap();
+        let mut engine = with_chronology(Chronology::Check);
+        let rejections = engine.apply_batch(rows);
+
+        let rejected: Vec<(RowNumber, Error)> =
+            rejections.into_iter().map(|r| (r.row, r.error)).collect();
+        assert_eq!(rejected, vec![(4, Error::OutOfOrder)]);
+        assert_eq!(
+            engine.account(2).map(Account::available),
+            Some(Decimal::new(1, 0)),
+            "rows without timestamp are not checked"
+        );
+    }
+
+    #[test]
+    fn test_pruning_keeps_the_outcome() {
+        let csv = "type, client, tx, amount, timestamp\n\
+                   deposit, 1, 1, 7.0, 2024-01-01T10:00:00Z\n\
+                   deposit, 1, 2, 10.0, 2024-03-01T10:00:00Z\n\
+                   deposit, 1, 1, 7.0, 2024-03-02T10:00:00Z\n\
+                   dispute, 1, 1, , 2024-03-03T10:00:00Z\n\
+                   deposit, 1, 3, 2.0, 2024-03-05T10:00:00Z\n\
+                   deposit, 1, 4, 3.0, 2024-04-20T10:00:00Z\n\
+                   dispute, 1, 3, , 2024-03-10T10:00:00Z";
+        let run = |prune_expired, stream| {
+            let mut engine = PaymentEngine::new(EngineConfig {
+                disputes: DisputeRules {
+                    window: Some(chrono::Duration::days(30)),
+                    prune_expired,
+                    ..DisputeRules::default()
+                },
+                ..EngineConfig::default()
+            });
+            let rejections = if stream {
+                let mut bytes = csv.as_bytes();
+                engine.apply_stream(csv_handler::stream_transactions(&mut bytes, false)).unwrap()
+            } else {
+                engine.apply_batch(csv_handler::read_transactions(&mut csv.as_bytes(), false).unwrap())
+            };
+            let rejected: Vec<(RowNumber, Error)> = rejections.into_iter().map(|r| (r.row, r.error)).collect();
+            (rejected, balances(&engine))
+        };
+
+        let (rejected, balances) = run(true, false);
+        assert_eq!(
+            rejected,
+            vec![
+                (4, Error::DuplicatedTransactionId),
+                (5, Error::DisputeWindowExpired),
+                (8, Error::DisputeWindowExpired)
+            ]
+        );
+        assert_eq!(balances[&1], (Decimal::new(22, 0), Decimal::ZERO, false));
+        let outcome = (rejected, balances);
+        assert_eq!(outcome, run(false, false), "pruning changed the outcome");
+        assert_eq!(outcome, run(true, true), "pruning changed the outcome of a stream");
+        assert_eq!(outcome, run(false, true));
+    }
+
+    #[test]
+    fn test_chronology_sort() {
+        let rows = csv_handler::read_transactions(&mut UNORDERED.as_bytes(), false).unwrap();
+        let mut engine = with_chronology(Chronology::Sort);
+        let rejections = engine.apply_batch(rows);
+
+        let rejected: Vec<(RowNumber, Error)> =
+            rejections.into_iter().map(|r| (r.row, r.error)).collect();
+        assert_eq!(rejected, vec![(5, Error::MissingTimestamp)]);
+        assert_eq!(
+            engine.account(1).map(Account::available),
+            Some(Decimal::new(6, 0))
+        );
+        assert_eq!(engine.account(2), None);
+
+        let mut bytes = UNORDERED.as_bytes();
+        let mut stream = with_chronology(Chronology::Sort);
+        let rejections = stream
+            .apply_stream(csv_handler::stream_transactions(&mut bytes, false))
+            .unwrap();
+        let rejected: Vec<(RowNumber, Error)> =
+            rejections.into_iter().map(|r| (r.row, r.error)).collect();
+        assert_eq!(
+            rejected,
+            vec![(4, Error::OutOfOrder)],
+            "a stream is only checked"
+        );
+    }
+
+    #[test]
+    fn test_transfer() {
+        let csv = "type, client, tx, amount, to\n\
+                   deposit, 1, 1, 10.0,\n\
+                   deposit, 3, 2, 1.0,\n\
+                   withdrawal, 2, 3, 4.0,\n\
+                   transfer, 1, 4, 6.0, 2\n\
+                   withdrawal, 2, 5, 4.0,\n\
+                   transfer, 1, 6, 5.0, 3\n\
+                   transfer, 1, 7, 1.0, 4\n\
+                   transfer, 1, 8, 1.0,";
+        let (batch, batch_rejections) = batch_fixture(csv);
+        let (stream, stream_rejections) = stream_fixture(csv);
+        assert_eq!(balances(&batch), balances(&stream));
+        assert_eq!(batch_rejections, stream_rejections);
+
+        let rejected: Vec<(RowNumber, Error)> = batch_rejections
+            .into_iter()
+            .map(|r| (r.row, r.error))
+            .collect();
+        assert_eq!(
+            rejected,
+            vec![
+                (4, Error::InsufficientFunds),
+                (7, Error::InsufficientFunds),
+                (9, Error::InvalidRecipient)
+            ]
+        );
+        let acc = balances(&batch);
+        assert_eq!(acc[&1], (Decimal::new(3, 0), Decimal::ZERO, false));
+        assert_eq!(acc[&2], (Decimal::new(2, 0), Decimal::ZERO, false));
+        assert_eq!(acc[&3], (Decimal::new(1, 0), Decimal::ZERO, false));
+        assert_eq!(acc[&4], (Decimal::new(1, 0), Decimal::ZERO, false));
+    }
+
+    #[test]
+    fn test_fees() {
+        let fees = FeeSchedule::from_toml(
+            "[[fee]]\ntype = \"withdrawal\"\nflat = \"0.5\"\n\
+             [[fee]]\ntype = \"transfer\"\npercent = \"10\"",
+        )
+        .unwrap();
+        let config = EngineConfig {
+            fees,
+            ..EngineConfig::default()
+        };
+        let csv = "type, client, tx, amount, to\n\
+                   deposit, 1, 1, 10.0,\n\
+                   deposit, 2, 2, 1.0,\n\
+                   withdrawal, 1, 3, 4.0,\n\
+                   withdrawal, 2, 4, 1.0,\n\
+                   transfer, 1, 5, 5.0, 2\n\
+                   withdrawal, 2, 6, 5.0,";
+        let rows = csv_handler::read_transactions(&mut csv.as_bytes(), false).unwrap();
+        let mut batch = PaymentEngine::new(config.clone());
+        batch.collect_fee_items();
+        let rejections = batch.apply_batch(rows);
+        let mut bytes = csv.as_bytes();
+        let mut stream = PaymentEngine::new(config.clone());
+        stream.collect_fee_items();
+        let stream_rejections = stream
+            .apply_stream(csv_handler::stream_transactions(&mut bytes, false))
+            .unwrap();
+        assert_eq!(balances(&batch), balances(&stream));
+        assert_eq!(rejections, stream_rejections);
+        assert_eq!(batch.fee_items(), stream.fee_items());
+
+        let rejected: Vec<(RowNumber, Error)> =
+            rejections.into_iter().map(|r| (r.row, r.error)).collect();
+        assert_eq!(rejected, vec![(5, Error::InsufficientFunds)], "the fee needs funds too");
+        let fees: Vec<(RowNumber, Decimal)> = batch.fee_items().iter().map(|f| (f.row, f.fee)).collect();
+        assert_eq!(
+            fees,
+            vec![(4, Decimal::new(5, 1)), (6, Decimal::new(5, 1)), (7, Decimal::new(5, 1))]
+        );
+        let acc = balances(&batch);
+        assert_eq!(acc[&1], (Decimal::new(0, 0), Decimal::ZERO, false));
+        assert_eq!(acc[&2], (Decimal::new(5, 1), Decimal::ZERO, false));
+        assert_eq!(batch.account(1).map(|a| a.fees_paid(DEFAULT_CURRENCY)), Some(Decimal::ONE));
+
+        let mut uncollected = PaymentEngine::new(config);
+        uncollected.apply_batch(csv_handler::read_transactions(&mut csv.as_bytes(), false).unwrap());
+        assert!(uncollected.fee_items().is_empty(), "line items are only kept when asked for");
+        assert_eq!(balances(&uncollected), balances(&batch));
+    }
+
+    #[test]
+    fn test_credit_limits() {
+        let limits = "client, limit\n1, 5.0";
+        let config = EngineConfig {
+            credit_limits: crate::credit::read_limits(&mut limits.as_bytes()).unwrap(),
+            ..EngineConfig::default()
+        };
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 1.0\n\
+                   withdrawal, 1, 2, 4.0\n\
+                   withdrawal, 2, 3, 1.0\n\
+                   withdrawal, 1, 4, 3.0";
+        let rows = csv_handler::read_transactions(&mut csv.as_bytes(), false).unwrap();
+        let mut batch = PaymentEngine::new(config.clone());
+        let rejections = batch.apply_batch(rows);
+        let mut bytes = csv.as_bytes();
+        let mut stream = PaymentEngine::new(config.clone());
+        let stream_rejections = stream
+            .apply_stream(csv_handler::stream_transactions(&mut bytes, false))
+            .unwrap();
+        assert_eq!(balances(&batch), balances(&stream));
+        assert_eq!(rejections, stream_rejections);
+
+        let rejected: Vec<(RowNumber, Error)> =
+            rejections.into_iter().map(|r| (r.row, r.error)).collect();
+        assert_eq!(
+            rejected,
+            vec![(4, Error::InsufficientFunds), (5, Error::CreditLimitExceeded)]
+        );
+        assert_eq!(batch.account(1).map(Account::available), Some(Decimal::new(-3, 0)));
+
+        let json = serde_json::to_string(&batch.state()).unwrap();
+        assert!(!json.contains("credit_limits"), "{}", json);
+        let saved = || serde_json::from_str::<EngineState>(&json).unwrap();
+        let restored = PaymentEngine::from_state(EngineConfig::default(), saved());
+        assert_eq!(
+            restored.account(1).map(|a| a.credit_limit(DEFAULT_CURRENCY)),
+            Some(Decimal::ZERO),
+            "the limits come from the config"
+        );
+        let restored = PaymentEngine::from_state(config, saved());
+        assert_eq!(restored.account(1), batch.account(1));
+    }
+
+    #[test]
+    fn test_transfer_to_locked_account() {
+        let csv = "type, client, tx, amount, to\n\
+                   deposit, 1, 1, 10.0,\n\
+                   freeze, 2, 2, ,\n\
+                   transfer, 1, 3, 6.0, 2\n\
+                   transfer, 3, 4, 6.0, 5";
+        let (engine, rejections) = batch_fixture(csv);
+
+        let rejected: Vec<(RowNumber, Error)> =
+            rejections.into_iter().map(|r| (r.row, r.error)).collect();
+        assert_eq!(
+            rejected,
+            vec![(4, Error::AccountLocked), (5, Error::InsufficientFunds)]
+        );
+        assert_eq!(
+            engine.account(1).map(Account::available),
+            Some(Decimal::new(10, 0))
+        );
+        assert_eq!(
+            engine.account(5),
+            None,
+            "failed transfer doesn't open an account"
+        );
+    }
+
+    #[test]
+    fn test_rejected_row_keeps_its_id() {
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 10.0\n\
+                   withdrawal, 1, 2, 50.0\n\
+                   deposit, 2, 2, 5.0\n\
+                   withdrawal, 1, 2, 5.0\n\
+                   deposit, 2, 3, 5.0";
+        for (engine, rejections) in [batch_fixture(csv), stream_fixture(csv)].iter() {
+            let rejected: Vec<(RowNumber, Error)> =
+                rejections.iter().map(|r| (r.row, r.error.clone())).collect();
+            assert_eq!(
+                rejected,
+                vec![
+                    (3, Error::InsufficientFunds),
+                    (4, Error::DuplicatedTransactionId),
+                    (5, Error::DuplicatedTransactionId)
+                ],
+                "the ID is used up for every client"
+            );
+            assert_eq!(engine.account(1).map(Account::available), Some(Decimal::new(10, 0)));
+            assert_eq!(engine.account(2).map(Account::available), Some(Decimal::new(5, 0)));
+        }
+    }
+
+    #[test]
+    fn test_reused_transfer_id() {
+        let csv = "type, client, tx, amount, to\n\
+                   deposit, 1, 1, 10.0,\n\
+                   transfer, 1, 2, 3.0, 2\n\
+                   transfer, 1, 2, 3.0, 2\n\
+                   transfer, 1, 1, 1.0, 2";
+        for (engine, rejections) in [batch_fixture(csv), stream_fixture(csv)].iter() {
+            let rejected: Vec<(RowNumber, Error)> =
+                rejections.iter().map(|r| (r.row, r.error.clone())).collect();
+            assert_eq!(
+                rejected,
+                vec![(4, Error::DuplicatedTransactionId), (5, Error::DuplicatedTransactionId)]
+            );
+            assert_eq!(engine.account(1).map(Account::available), Some(Decimal::new(7, 0)));
+            assert_eq!(engine.account(2).map(Account::available), Some(Decimal::new(3, 0)));
+        }
+    }
+
+    #[test]
+    fn test_invalid_amount_does_not_claim_id() {
+        let csv = "type, client, tx, amount\ndeposit, 1, 1, -5.0\ndeposit, 2, 1, 1.00001\ndeposit, 3, 1, 2.0";
+
+        let (engine, rejections) = batch_fixture(csv);
+        let rejected: Vec<(RowNumber, Error)> =
+            rejections.into_iter().map(|r| (r.row, r.error)).collect();
+        assert_eq!(
+            rejected,
+            vec![(2, Error::NegativeAmount), (3, Error::PrecisionExceeded)]
+        );
+        assert_eq!(
+            engine.account(3).map(Account::available),
+            Some(Decimal::new(2, 0))
+        );
+        assert_eq!(engine.account(1), None);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::journal::JournalEntry;
+use crate::transaction::*;
+
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+
+/// Fee charged for every accepted transaction of a type: a flat part plus a percentage of the
+/// amount, kept between the optional minimum and maximum.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FeeRule {
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+    #[serde(default)]
+    pub flat: Decimal,
+    #[serde(default)]
+    pub percent: Decimal,
+    pub min: Option<Decimal>,
+    pub max: Option<Decimal>,
+}
+
+impl FeeRule {
+    fn fee(&self, amount: Decimal) -> Option<Decimal> {
+        let mut fee = amount
+            .checked_mul(self.percent)?
+            .checked_div(Decimal::ONE_HUNDRED)?
+            .checked_add(self.flat)?;
+        if let Some(min) = self.min {
+            fee = fee.max(min);
+        }
+        if let Some(max) = self.max {
+            fee = fee.min(max);
+        }
+        Some(fee)
+    }
+}
+
+#[derive(Debug)]
+pub enum FeeError {
+    Toml(toml::de::Error),
+    NegativeFee { transaction_type: TransactionType },
+    InvalidRange { transaction_type: TransactionType },
+    DuplicatedRule { transaction_type: TransactionType },
+}
+
+impl fmt::Display for FeeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FeeError::Toml(e) => write!(f, "{}", e),
+            FeeError::NegativeFee { transaction_type } => {
+                write!(f, "negative fee for {:?}", transaction_type)
+            }
+            FeeError::InvalidRange { transaction_type } => {
+                write!(f, "minimum fee is above the maximum for {:?}", transaction_type)
+            }
+            FeeError::DuplicatedRule { transaction_type } => {
+                write!(f, "more than one fee for {:?}", transaction_type)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FeeError {}
+
+impl From<toml::de::Error> for FeeError {
+    fn from(e: toml::de::Error) -> Self {
+        FeeError::Toml(e)
+    }
+}
+
+/// Fee rules of the transaction types, at most one per type.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FeeSchedule {
+    #[serde(default, rename = "fee")]
+    rules: Vec<FeeRule>,
+}
+
+impl FeeSchedule {
+    /// Reads the schedule from a TOML config with one `[[fee]]` table per rule.
+    pub fn from_toml(config: &str) -> std::result::Result<FeeSchedule, FeeError> {
+        let schedule: FeeSchedule = toml::from_str(config)?;
+
+        for (idx, rule) in schedule.rules.iter().enumerate() {
+            let transaction_type = rule.transaction_type.clone();
+            let amounts = [Some(rule.flat), Some(rule.percent), rule.min, rule.max];
+            if amounts.iter().flatten().any(Decimal::is_sign_negative) {
+                return Err(FeeError::NegativeFee { transaction_type });
+            }
+            if let (Some(min), Some(max)) = (rule.min, rule.max) {
+                if min > max {
+                    return Err(FeeError::InvalidRange { transaction_type });
+                }
+            }
+            if schedule.rules[..idx]
+                .iter()
+                .any(|r| r.transaction_type == transaction_type)
+            {
+                return Err(FeeError::DuplicatedRule { transaction_type });
+            }
+        }
+
+        Ok(schedule)
+    }
+
+    /// Fee of a transaction of the type with the given amount, rounded to `scale` decimal places.
+    /// Zero if there's no rule for the type.
+    pub fn fee(&self, transaction_type: &TransactionType, amount: Decimal, scale: u32) -> Result<Decimal> {
+        let rule = match self.rules.iter().find(|r| r.transaction_type == *transaction_type) {
+            Some(rule) => rule,
+            None => return Ok(Decimal::ZERO),
+        };
+        let fee = rule.fee(amount).ok_or(Error::BalanceOverflow)?;
+        Ok(fee.round_dp_with_strategy(scale, RoundingStrategy::MidpointAwayFromZero))
+    }
+}
+
+/// A fee taken from the available funds in the currency of the balance changed by the transaction.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Fee {
+    pub currency: Currency,
+    pub amount: Decimal,
+}
+
+/// A fee charged for an accepted transaction, linked to it by row and transaction ID.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct FeeItem {
+    pub row: RowNumber,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+    pub currency: Currency,
+    pub fee: Decimal,
+}
+
+impl FeeItem {
+    /// Fee line item of the journaled transaction, if a fee was charged for it.
+    pub fn from_entry(entry: &JournalEntry) -> Option<FeeItem> {
+        let tr = &entry.transaction;
+        let fee = entry.fee.as_ref()?;
+        Some(FeeItem {
+            row: entry.row,
+            client: tr.client_id,
+            tx: tr.transaction_id,
+            transaction_type: tr.transaction_type.clone(),
+            currency: fee.currency.clone(),
+            fee: fee.amount,
+        })
+    }
+}
+
+pub fn write_fee_items(items: &[FeeItem], output: &mut dyn io::Write) -> csv::Result<()> {
+    let mut writer = csv::Writer::from_writer(output);
+    for item in items {
+        writer.serialize(item)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [[fee]]
+        type = "withdrawal"
+        flat = "0.25"
+        percent = 1.5
+        min = "0.5"
+        max = "10"
+
+        [[fee]]
+        type = "chargeback"
+        flat = "15"
+    "#;
+
+    #[test]
+    fn test_fee() {
+        let fees = FeeSchedule::from_toml(CONFIG).expect("reading fees failed");
+        let fee = |transaction_type, amount| fees.fee(&transaction_type, amount, 4).unwrap();
+
+        // 0.25 + 1.5% of 100
+        assert_eq!(fee(TransactionType::Withdrawal, Decimal::new(100, 0)), Decimal::new(175, 2));
+        assert_eq!(fee(TransactionType::Withdrawal, Decimal::new(1, 0)), Decimal::new(5, 1), "minimum");
+        assert_eq!(fee(TransactionType::Withdrawal, Decimal::new(1000, 0)), Decimal::new(10, 0), "maximum");
+        assert_eq!(
+            fee(TransactionType::Withdrawal, Decimal::new(333333, 4)),
+            Decimal::new(7500, 4),
+            "rounded to the scale"
+        );
+        assert_eq!(fee(TransactionType::Chargeback, Decimal::new(5, 0)), Decimal::new(15, 0));
+        assert_eq!(fee(TransactionType::Deposit, Decimal::new(5, 0)), Decimal::ZERO);
+        assert_eq!(FeeSchedule::default().fee(&TransactionType::Withdrawal, Decimal::ONE, 4), Ok(Decimal::ZERO));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let res = FeeSchedule::from_toml("[[fee]]\ntype = \"deposit\"\nflat = \"-1\"");
+        assert!(matches!(res, Err(FeeError::NegativeFee { .. })), "{:?}", res);
+
+        let res = FeeSchedule::from_toml("[[fee]]\ntype = \"deposit\"\nmin = \"2\"\nmax = \"1\"");
+        assert!(matches!(res, Err(FeeError::InvalidRange { .. })), "{:?}", res);
+
+        let res = FeeSchedule::from_toml("[[fee]]\ntype = \"deposit\"\n[[fee]]\ntype = \"deposit\"");
+        assert!(matches!(res, Err(FeeError::DuplicatedRule { .. })), "{:?}", res);
+
+        let res = FeeSchedule::from_toml("[[fee]]\ntype = \"bonus\"");
+        assert!(matches!(res, Err(FeeError::Toml(_))), "{:?}", res);
+        let res = FeeSchedule::from_toml("[[fee]]\ntype = \"deposit\"\nflta = \"1\"");
+        assert!(matches!(res, Err(FeeError::Toml(_))), "unknown keys are rejected: {:?}", res);
+    }
+}
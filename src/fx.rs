@@ -0,0 +1,294 @@
+use crate::account::{round_output, Account};
+use crate::transaction::*;
+
+use chrono::NaiveDate;
+use csv::{Position, ReaderBuilder, StringRecord, Trim};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+
+/// One row of the FX rate table: one unit of the first currency of the pair (e.g. `EUR/USD`)
+/// is worth `rate` units of the second one from the effective date on.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+struct FxRateRow {
+    pair: String,
+    rate: Decimal,
+    effective_date: NaiveDate,
+}
+
+#[derive(Debug)]
+pub enum FxError {
+    Csv(csv::Error),
+    InvalidPair { row: RowNumber, pair: String },
+    InvalidRate { row: RowNumber },
+    MissingRate { from: Currency, to: Currency },
+    Overflow { client: ClientId },
+}
+
+impl fmt::Display for FxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FxError::Csv(e) => write!(f, "{}", e),
+            FxError::InvalidPair { row, pair } => {
+                write!(f, "invalid currency pair in row {}: {}", row, pair)
+            }
+            FxError::InvalidRate { row } => write!(f, "rate is not positive in row {}", row),
+            FxError::MissingRate { from, to } => write!(f, "no rate from {} to {}", from, to),
+            FxError::Overflow { client } => {
+                write!(f, "converted balance of client {} is out of range", client)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FxError {}
+
+impl From<csv::Error> for FxError {
+    fn from(e: csv::Error) -> Self {
+        FxError::Csv(e)
+    }
+}
+
+/// Rates of every currency pair, ordered by effective date.
+#[derive(Debug, Default)]
+pub struct FxTable {
+    rates: HashMap<(Currency, Currency), Vec<(NaiveDate, Decimal)>>,
+}
+
+impl FxTable {
+    pub fn add(&mut self, from: &str, to: &str, effective_date: NaiveDate, rate: Decimal) {
+        let rates = self
+            .rates
+            .entry((from.to_string(), to.to_string()))
+            .or_default();
+        let idx = rates.partition_point(|(date, _)| *date <= effective_date);
+        rates.insert(idx, (effective_date, rate));
+    }
+
+    /// Latest rate effective on the date, using the inverse of the opposite pair if the pair itself is missing.
+    /// Rates which only take effect after the date are never used.
+    pub fn rate(&self, from: &str, to: &str, date: NaiveDate) -> Option<Decimal> {
+        if from == to {
+            return Some(Decimal::ONE);
+        }
+        let effective = |from: &str, to: &str| {
+            self.rates
+                .get(&(from.to_string(), to.to_string()))?
+                .iter()
+                .rev()
+                .find(|(effective_date, _)| *effective_date <= date)
+                .map(|(_, rate)| *rate)
+        };
+        effective(from, to).or_else(|| Decimal::ONE.checked_div(effective(to, from)?))
+    }
+}
+
+pub fn read_rates(input: &mut dyn io::Read) -> std::result::Result<FxTable, FxError> {
+    let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(input);
+    let mut table = FxTable::default();
+
+    let headers = reader.headers()?.clone();
+    let mut record = StringRecord::new();
+    while reader.read_record(&mut record)? {
+        let rate: FxRateRow = record.deserialize(Some(&headers))?;
+        let row = record.position().map_or(0, Position::line);
+        let currencies: Vec<String> = rate.pair.split('/').map(str::to_ascii_uppercase).collect();
+        let (from, to) = match currencies.as_slice() {
+            [from, to] if from.len() == 3 && to.len() == 3 => (from, to),
+            _ => {
+                return Err(FxError::InvalidPair {
+                    row,
+                    pair: rate.pair,
+                })
+            }
+        };
+        if rate.rate <= Decimal::ZERO {
+            return Err(FxError::InvalidRate { row });
+        }
+        table.add(from, to, rate.effective_date, rate.rate);
+    }
+
+    Ok(table)
+}
+
+/// Balances of a client converted to the reporting currency and summed over all currencies.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ConsolidatedOutput {
+    pub client: ClientId,
+    pub currency: Currency,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+/// Converts the balances before rounding, so the result is rounded only once, like `AccountOutput`.
+/// Rows are ordered by client ID.
+pub fn consolidate<'a, I>(
+    accounts: I,
+    rates: &FxTable,
+    currency: &str,
+    date: NaiveDate,
+) -> std::result::Result<Vec<ConsolidatedOutput>, FxError>
+where
+    I: IntoIterator<Item = &'a Account>,
+{
+    let mut outputs = Vec::new();
+
+    for acc in accounts {
+        let overflow = || FxError::Overflow {
+            client: acc.client_id(),
+        };
+        let (mut available, mut held) = (Decimal::ZERO, Decimal::ZERO);
+        for (from, balance) in acc.balances() {
+            let rate = rates
+                .rate(from, currency, date)
+                .ok_or_else(|| FxError::MissingRate {
+                    from: from.clone(),
+                    to: currency.to_string(),
+                })?;
+            let convert = |amount: Decimal| amount.checked_mul(rate).ok_or_else(overflow);
+            available = available
+                .checked_add(convert(balance.available)?)
+                .ok_or_else(overflow)?;
+            held = held
+                .checked_add(convert(balance.held)?)
+                .ok_or_else(overflow)?;
+        }
+        let total = available.checked_add(held).ok_or_else(overflow)?;
+
+        outputs.push(ConsolidatedOutput {
+            client: acc.client_id(),
+            currency: currency.to_string(),
+            available: round_output(available),
+            held: round_output(held),
+            total: round_output(total),
+            locked: acc.is_locked(),
+        });
+    }
+
+    outputs.sort_by_key(|o| o.client);
+    Ok(outputs)
+}
+
+pub fn write_consolidated(
+    outputs: &[ConsolidatedOutput],
+    output: &mut dyn io::Write,
+) -> csv::Result<()> {
+    let mut writer = csv::Writer::from_writer(output);
+    for out in outputs {
+        writer.serialize(out)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::PaymentEngine;
+
+    const RATES: &str = "pair, rate, effective_date\n\
+                         EUR/USD, 1.10, 2024-01-01\n\
+                         EUR/USD, 1.20, 2024-02-01\n\
+                         gbp/eur, 1.15, 2024-01-01\n";
+
+    fn date(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    fn table() -> FxTable {
+        read_rates(&mut RATES.as_bytes()).expect("reading rates failed")
+    }
+
+    #[test]
+    fn test_rate_by_date() {
+        let table = table();
+        assert_eq!(table.rate("EUR", "USD", date("2024-06-01")), Some(Decimal::new(120, 2)));
+        assert_eq!(
+            table.rate("EUR", "USD", date("2024-01-31")),
+            Some(Decimal::new(110, 2)),
+            "a rate is not used before its effective date"
+        );
+        assert_eq!(table.rate("EUR", "USD", date("2023-12-31")), None);
+        assert_eq!(table.rate("GBP", "EUR", date("2024-06-01")), Some(Decimal::new(115, 2)));
+        assert_eq!(table.rate("USD", "USD", date("2023-12-31")), Some(Decimal::ONE));
+    }
+
+    #[test]
+    fn test_inverse_rate() {
+        let rate = table().rate("USD", "EUR", date("2024-06-01")).unwrap();
+        assert_eq!(round_output(rate), Decimal::new(8333, 4));
+        assert_eq!(
+            table().rate("USD", "GBP", date("2024-06-01")),
+            None,
+            "rates are not chained"
+        );
+    }
+
+    #[test]
+    fn test_invalid_rows() {
+        let res = read_rates(&mut "pair, rate, effective_date\nEURUSD, 1.1, 2024-01-01".as_bytes());
+        assert!(
+            matches!(res, Err(FxError::InvalidPair { row: 2, .. })),
+            "{:?}",
+            res
+        );
+
+        let res = read_rates(&mut "pair, rate, effective_date\nEUR/USD, 0, 2024-01-01".as_bytes());
+        assert!(
+            matches!(res, Err(FxError::InvalidRate { row: 2 })),
+            "{:?}",
+            res
+        );
+    }
+
+    fn deposit(
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        amount: Decimal,
+        currency: &str,
+    ) -> Transaction {
+        Transaction {
+            transaction_type: TransactionType::Deposit,
+            client_id,
+            transaction_id,
+            amount: Some(amount),
+            reason: None,
+            currency: Some(currency.to_string()),
+            timestamp: None,
+            recipient: None,
+        }
+    }
+
+    #[test]
+    fn test_consolidate() {
+        let mut engine = PaymentEngine::default();
+        let transactions = [
+            deposit(2, 1, Decimal::new(10, 0), "USD"),
+            deposit(2, 2, Decimal::new(5, 0), "EUR"),
+            deposit(1, 3, Decimal::new(100, 0), "GBP"),
+        ];
+        for tr in transactions.iter() {
+            engine.apply(tr).unwrap();
+        }
+
+        let outputs = consolidate(engine.accounts(), &table(), "EUR", date("2024-06-01")).unwrap();
+        let totals: Vec<(ClientId, Decimal)> =
+            outputs.iter().map(|o| (o.client, o.total)).collect();
+        // 10 USD / 1.20 = 8.33333.. EUR
+        assert_eq!(
+            totals,
+            vec![(1, Decimal::new(115, 0)), (2, Decimal::new(133333, 4))]
+        );
+
+        let res = consolidate(engine.accounts(), &table(), "USD", date("2024-06-01"));
+        assert!(
+            matches!(res, Err(FxError::MissingRate { ref from, .. }) if from == "GBP"),
+            "{:?}",
+            res
+        );
+    }
+}
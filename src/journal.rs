@@ -0,0 +1,289 @@
+use crate::account::Account;
+use crate::engine::{EngineConfig, PaymentEngine};
+use crate::fees::Fee;
+use crate::state::EngineState;
+use crate::transaction::*;
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// An accepted transaction together with the state change it caused in the balance of its currency
+/// (the currency of the disputed transaction for disputes).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct JournalEntry {
+    pub row: RowNumber,
+    #[serde(flatten)]
+    pub transaction: Transaction,
+    pub available_change: Decimal,
+    pub held_change: Decimal,
+    pub locked: bool, // lock flag after the transaction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee: Option<Fee>, // included in available_change
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub received: Option<Decimal>, // available change of the recipient of a transfer
+}
+
+/// Append-only event log, one JSON entry per line.
+///
+/// Write errors are kept until `finish` so processing doesn't have to stop for them.
+pub struct Journal {
+    writer: Box<dyn Write + Send>,
+    error: Option<io::Error>,
+}
+
+impl Journal {
+    pub fn new(writer: Box<dyn Write + Send>) -> Journal {
+        Journal {
+            writer,
+            error: None,
+        }
+    }
+
+    /// Opens the journal file for appending, creating it if needed.
+    pub fn open(path: &Path) -> io::Result<Journal> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Journal::new(Box::new(io::BufWriter::new(file))))
+    }
+
+    pub fn record(&mut self, entry: &JournalEntry) {
+        if self.error.is_some() {
+            return;
+        }
+        let res = serde_json::to_writer(&mut self.writer, entry)
+            .map_err(io::Error::from)
+            .and_then(|_| self.writer.write_all(b"\n"));
+        if let Err(e) = res {
+            self.error = Some(e);
+        }
+    }
+
+    /// Flushes the journal, returning the first error which happened while writing it.
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.writer.flush()
+    }
+}
+
+impl fmt::Debug for Journal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Journal")
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+pub fn read_journal<'a>(
+    input: &'a mut dyn io::Read,
+) -> impl Iterator<Item = io::Result<JournalEntry>> + 'a {
+    serde_json::Deserializer::from_reader(input)
+        .into_iter::<JournalEntry>()
+        .map(|entry| entry.map_err(io::Error::from))
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    /// The transaction of the entry was rejected during the replay.
+    Rejected { entry: usize, error: Error },
+    /// The replayed transaction caused a different state change than the recorded one.
+    Mismatch {
+        entry: usize,
+        recorded: Box<JournalEntry>,
+        replayed: Box<JournalEntry>,
+    },
+}
+
+impl From<io::Error> for ReplayError {
+    fn from(e: io::Error) -> Self {
+        ReplayError::Io(e)
+    }
+}
+
+/// Rebuilds the engine by applying the journaled transactions again, checking every state change
+/// against the recorded one. Entries are counted from 1.
+pub fn replay<I>(entries: I, config: EngineConfig) -> std::result::Result<PaymentEngine, ReplayError>
+where
+    I: IntoIterator<Item = io::Result<JournalEntry>>,
+{
+    let mut engine = PaymentEngine::new(config);
+
+    for (idx, entry) in entries.into_iter().enumerate() {
+        let recorded = entry?;
+        let replayed = engine
+            .apply_row(recorded.row, &recorded.transaction)
+            .map_err(|error| ReplayError::Rejected {
+                entry: idx + 1,
+                error,
+            })?;
+        if replayed != recorded {
+            return Err(ReplayError::Mismatch {
+                entry: idx + 1,
+                recorded: Box::new(recorded),
+                replayed: Box::new(replayed),
+            });
+        }
+    }
+
+    Ok(engine)
+}
+
+/// Clients whose replayed account differs from the one in the snapshot.
+/// A missing account is the same as an empty one, since rejected transactions are not journaled
+/// but they may still have opened an account. Credit limits are not saved, so they are not compared.
+pub fn verify(engine: &PaymentEngine, snapshot: &EngineState) -> Vec<ClientId> {
+    let expected: HashMap<ClientId, &Account> = snapshot
+        .accounts
+        .iter()
+        .map(|acc| (acc.client_id(), acc))
+        .collect();
+
+    let mut clients: Vec<ClientId> = engine
+        .accounts()
+        .map(Account::client_id)
+        .chain(expected.keys().copied())
+        .collect();
+    clients.sort_unstable();
+    clients.dedup();
+
+    clients
+        .into_iter()
+        .filter(|cid| {
+            let comparable = |acc: Option<&Account>| {
+                let mut acc = acc.cloned().unwrap_or_else(|| Account::new(*cid));
+                acc.set_credit_limits(BTreeMap::new());
+                acc
+            };
+            comparable(engine.account(*cid)) != comparable(expected.get(cid).copied())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv_handler;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn journaled_run(csv: &str, batch: bool) -> (PaymentEngine, Vec<u8>) {
+        let buffer = SharedBuffer::default();
+        let mut engine = PaymentEngine::default();
+        engine.set_journal(Journal::new(Box::new(buffer.clone())));
+
+        let mut bytes = csv.as_bytes();
+        if batch {
+            let rows = csv_handler::read_transactions(&mut bytes, false).unwrap();
+            engine.apply_batch(rows);
+        } else {
+            engine
+                .apply_stream(csv_handler::stream_transactions(&mut bytes, false))
+                .unwrap();
+        }
+        engine.take_journal().unwrap().finish().unwrap();
+
+        let journal = buffer.0.lock().unwrap().clone();
+        (engine, journal)
+    }
+
+    #[test]
+    fn test_entries() {
+        let (_, journal) = journaled_run(include_str!("../test/example_2_all_types.csv"), false);
+        let entries: Vec<JournalEntry> = read_journal(&mut journal.as_slice())
+            .collect::<io::Result<_>>()
+            .unwrap();
+
+        assert_eq!(entries.len(), 9);
+        let chargeback = &entries[8];
+        assert_eq!(chargeback.row, 10);
+        assert_eq!(chargeback.transaction.transaction_type, TransactionType::Chargeback);
+        assert_eq!(chargeback.available_change, Decimal::ZERO);
+        assert_eq!(chargeback.held_change, Decimal::new(-2, 0));
+        assert!(chargeback.locked);
+    }
+
+    #[test]
+    fn test_batch_and_stream_journals_match() {
+        let csv = include_str!("../test/example_3_large.csv");
+        assert_eq!(journaled_run(csv, true).1, journaled_run(csv, false).1);
+    }
+
+    #[test]
+    fn test_replay() {
+        let (engine, journal) = journaled_run(include_str!("../test/example_4_large_many_client.csv"), true);
+
+        let replayed = replay(read_journal(&mut journal.as_slice()), EngineConfig::default())
+            .expect("replay failed");
+        assert!(verify(&replayed, &engine.state()).is_empty());
+    }
+
+    #[test]
+    fn test_replay_detects_tampering() {
+        let (_, journal) = journaled_run(include_str!("../test/example_1.csv"), false);
+        let mut entries: Vec<JournalEntry> = read_journal(&mut journal.as_slice())
+            .collect::<io::Result<_>>()
+            .unwrap();
+        entries[1].available_change = Decimal::new(20, 0);
+
+        let res = replay(entries.into_iter().map(Ok), EngineConfig::default());
+        assert!(
+            matches!(res, Err(ReplayError::Mismatch { entry: 2, .. })),
+            "tampered entry should be found"
+        );
+    }
+
+    #[test]
+    fn test_transfer_entries() {
+        let (engine, journal) = journaled_run(include_str!("../test/example_7_transfers.csv"), true);
+        let entries: Vec<JournalEntry> = read_journal(&mut journal.as_slice())
+            .collect::<io::Result<_>>()
+            .unwrap();
+
+        let transfers: Vec<&JournalEntry> = entries
+            .iter()
+            .filter(|e| e.transaction.transaction_type == TransactionType::Transfer)
+            .collect();
+        assert_eq!(transfers.len(), 4);
+        for entry in transfers {
+            assert_eq!(entry.received, entry.transaction.amount);
+            assert_eq!(entry.available_change + entry.received.unwrap(), Decimal::ZERO, "row {}", entry.row);
+        }
+        assert!(entries
+            .iter()
+            .filter(|e| e.transaction.transaction_type != TransactionType::Transfer)
+            .all(|e| e.received.is_none()));
+
+        let replayed = replay(entries.into_iter().map(Ok), EngineConfig::default()).unwrap();
+        assert!(verify(&replayed, &engine.state()).is_empty());
+    }
+
+    #[test]
+    fn test_verify_reports_differences() {
+        let (engine, journal) = journaled_run(include_str!("../test/example_1.csv"), false);
+        let mut entries: Vec<JournalEntry> = read_journal(&mut journal.as_slice())
+            .collect::<io::Result<_>>()
+            .unwrap();
+        entries.retain(|e| e.transaction.client_id != 2);
+
+        let replayed = replay(entries.into_iter().map(Ok), EngineConfig::default()).unwrap();
+        assert_eq!(verify(&replayed, &engine.state()), vec![2]);
+    }
+}
@@ -0,0 +1,21 @@
+//! Payment engine which applies deposits, withdrawals and disputes to client accounts.
+//!
+//! The `PaymentEngine` is the main entry point, the CLI in `main.rs` is a thin wrapper around it.
+
+pub mod account;
+pub mod admin;
+pub mod credit;
+pub mod csv_handler;
+pub mod engine;
+pub mod fees;
+pub mod fx;
+pub mod journal;
+pub mod registry;
+pub mod rejection;
+pub mod state;
+pub mod transaction;
+pub mod validation;
+
+pub use account::{Account, AccountOutput};
+pub use engine::{EngineConfig, PaymentEngine};
+pub use transaction::{ClientId, Error, Transaction, TransactionId, TransactionType};
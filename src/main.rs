@@ -1,11 +1,24 @@
-mod account;
-mod csv_handler;
-mod transaction;
+use payment_engine::account::{DisputeRules, OutputOrder, WithdrawalDisputes};
+use payment_engine::admin;
+use payment_engine::credit::{self, CreditLimits};
+use payment_engine::csv_handler::{self, MalformedRow};
+use payment_engine::engine::Chronology;
+use payment_engine::fees::{self, FeeSchedule};
+use payment_engine::fx::{self, FxTable};
+use payment_engine::journal::{self, Journal};
+use payment_engine::registry::DuplicatePolicy;
+use payment_engine::rejection::{self, Rejection, ReportFormat};
+use payment_engine::state::{self, EngineState};
+use payment_engine::transaction::Row;
+use payment_engine::validation::{AmountRules, PrecisionPolicy};
+use payment_engine::{EngineConfig, PaymentEngine};
 
-use clap::{App, Arg, ArgMatches};
-use std::fs::File;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use clap::{value_t, App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::fs::{self, File};
+use std::path::Path;
 use std::{io, process};
-use std::time::Instant;
+use std::time::{Instant, SystemTime};
 
 const APP_NAME: &str = "Payment Engine";
 const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -13,6 +26,7 @@ const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 fn parse_args() -> ArgMatches<'static> {
     App::new(APP_NAME)
         .version(APP_VERSION)
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("INPUT")
                 .help("CSV file to use")
@@ -24,33 +38,411 @@ fn parse_args() -> ArgMatches<'static> {
                 .long("verbose")
                 .help("Print progress data"),
         )
+        .arg(
+            Arg::with_name("stream")
+                .short("s")
+                .long("stream")
+                .help("Process rows one at a time instead of loading the whole file (lower memory usage)"),
+        )
+        .arg(
+            Arg::with_name("duplicates")
+                .short("d")
+                .long("duplicates")
+                .takes_value(true)
+                .value_name("POLICY")
+                .possible_values(&DuplicatePolicy::VALUES)
+                .default_value("reject")
+                .help("Handling of transaction IDs reused across clients"),
+        )
+        .arg(
+            Arg::with_name("no-redispute")
+                .long("no-redispute")
+                .help("Reject disputes of transactions which were already disputed and resolved"),
+        )
+        .arg(
+            Arg::with_name("withdrawal-disputes")
+                .long("withdrawal-disputes")
+                .takes_value(true)
+                .value_name("POLICY")
+                .possible_values(&WithdrawalDisputes::VALUES)
+                .default_value("symmetric")
+                .help("Handling of disputed withdrawals"),
+        )
+        .arg(
+            Arg::with_name("dispute-window")
+                .long("dispute-window")
+                .takes_value(true)
+                .value_name("DAYS")
+                .help("Reject disputes which come later than this many days after the disputed transaction"),
+        )
+        .arg(
+            Arg::with_name("prune-expired")
+                .long("prune-expired")
+                .requires("dispute-window")
+                .help("Forget transactions which can no longer be disputed, to save memory"),
+        )
+        .arg(
+            Arg::with_name("scale")
+                .long("scale")
+                .takes_value(true)
+                .value_name("DIGITS")
+                .default_value("4")
+                .help("Maximum number of decimal places of the amounts"),
+        )
+        .arg(
+            Arg::with_name("precision")
+                .long("precision")
+                .takes_value(true)
+                .possible_values(&PrecisionPolicy::VALUES)
+                .default_value("reject")
+                .help("Handling of amounts with more decimal places than the scale"),
+        )
+        .arg(
+            Arg::with_name("chronology")
+                .long("chronology")
+                .takes_value(true)
+                .possible_values(&Chronology::VALUES)
+                .default_value("trust")
+                .help("Handling of the timestamp column: trust the file order, check it, or sort by time (checks only when streaming)"),
+        )
+        .arg(
+            Arg::with_name("sort")
+                .long("sort")
+                .takes_value(true)
+                .possible_values(&OutputOrder::VALUES)
+                .default_value("client")
+                .help("Row order of the account output, ties are ordered by client"),
+        )
+        .arg(
+            Arg::with_name("rejections")
+                .short("r")
+                .long("rejections")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Write the skipped transactions to this file (JSON for .json files, CSV otherwise)"),
+        )
+        .arg(
+            Arg::with_name("lenient")
+                .long("lenient")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Skip the rows which cannot be parsed instead of stopping, and write them with the parse error to this CSV file"),
+        )
+        .arg(
+            Arg::with_name("admin-log")
+                .long("admin-log")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Write the accepted administrative transactions (unlock, freeze, close, adjust) to this CSV file"),
+        )
+        .arg(
+            Arg::with_name("fees")
+                .long("fees")
+                .takes_value(true)
+                .value_name("CONFIG")
+                .help("TOML file with the fees charged per transaction type"),
+        )
+        .arg(
+            Arg::with_name("fee-log")
+                .long("fee-log")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Write the charged fees to this CSV file, one line per fee"),
+        )
+        .arg(
+            Arg::with_name("credit-limits")
+                .long("credit-limits")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("CSV file with the credit limits of the clients (client, optional currency, limit)"),
+        )
+        .arg(
+            Arg::with_name("fx-rates")
+                .long("fx-rates")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("CSV table of FX rates (pair, rate, effective_date) for the consolidated report"),
+        )
+        .arg(
+            Arg::with_name("consolidated")
+                .long("consolidated")
+                .takes_value(true)
+                .value_name("FILE")
+                .requires("fx-rates")
+                .help("Write the total of every client converted to the reporting currency to this CSV file"),
+        )
+        .arg(
+            Arg::with_name("report-currency")
+                .long("report-currency")
+                .takes_value(true)
+                .value_name("CURRENCY")
+                .default_value("EUR")
+                .help("Currency of the consolidated report"),
+        )
+        .arg(
+            Arg::with_name("fx-date")
+                .long("fx-date")
+                .takes_value(true)
+                .value_name("YYYY-MM-DD")
+                .help("Use the FX rates effective on this date instead of today"),
+        )
+        .arg(
+            Arg::with_name("load-state")
+                .long("load-state")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Continue from the engine state saved by a previous run"),
+        )
+        .arg(
+            Arg::with_name("save-state")
+                .long("save-state")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Save the full engine state (including dispute history) for the next run"),
+        )
+        .arg(
+            Arg::with_name("journal")
+                .short("j")
+                .long("journal")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Append every accepted transaction and the state change it caused to this file"),
+        )
         .arg(
             Arg::with_name("timed")
                 .short("t")
                 .long("timed")
                 .help("Measure execution time"),
         )
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("Rebuild the accounts from a journal, optionally checking them against a saved state")
+                .arg(
+                    Arg::with_name("JOURNAL")
+                        .help("Journal file to replay")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("against")
+                        .long("against")
+                        .takes_value(true)
+                        .value_name("STATE_FILE")
+                        .help("State saved with --save-state which the replayed accounts must match"),
+                ),
+        )
         .get_matches()
 }
 
-fn process_file(mut file: File, verbose: bool) {
-    let tr_result = csv_handler::read_transactions(&mut file, verbose);
-    match tr_result {
-        Ok(transactions) => {
-            if verbose {
-                println!("Transactions loaded: {}", transactions.len());
-            }
+fn load_transactions(
+    engine: &mut PaymentEngine,
+    file: &mut File,
+    stream: bool,
+    malformed: Option<&mut Vec<MalformedRow>>,
+) -> csv::Result<Vec<Rejection>> {
+    let rows = csv_handler::stream_transactions(file, engine.config().verbose);
+    match malformed {
+        Some(malformed) => apply_rows(engine, csv_handler::skip_malformed(rows, malformed), stream),
+        None => apply_rows(engine, rows, stream),
+    }
+}
+
+fn apply_rows<I>(engine: &mut PaymentEngine, rows: I, stream: bool) -> csv::Result<Vec<Rejection>>
+where
+    I: Iterator<Item = csv::Result<Row>>,
+{
+    if stream {
+        return engine.apply_stream(rows);
+    }
+
+    let transactions = rows.collect::<csv::Result<Vec<Row>>>()?;
+    if engine.config().verbose {
+        println!("Transactions loaded: {}", transactions.len());
+    }
+    Ok(engine.apply_batch(transactions))
+}
+
+fn write_malformed_rows(rows: &[MalformedRow], path: &Path) -> csv::Result<()> {
+    let mut file = File::create(path)?;
+    csv_handler::write_malformed_rows(rows, &mut file)
+}
+
+fn write_rejections(rejections: &[Rejection], path: &Path) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    rejection::write_rejections(rejections, ReportFormat::from_path(path), &mut file)
+}
+
+fn write_admin_log(engine: &PaymentEngine, path: &Path) -> csv::Result<()> {
+    let mut file = File::create(path)?;
+    admin::write_admin_actions(engine.admin_actions(), &mut file)
+}
 
-            let accounts = account::process_all(transactions, verbose);
-            if verbose {
+fn write_fee_log(engine: &PaymentEngine, path: &Path) -> csv::Result<()> {
+    let mut file = File::create(path)?;
+    fees::write_fee_items(engine.fee_items(), &mut file)
+}
+
+fn read_state_file(path: &Path) -> EngineState {
+    let mut file = File::open(path).unwrap_or_else(|e| {
+        eprintln!("Opening of state file failed! Error: {:?}", e);
+        process::exit(2)
+    });
+    state::read_state(&mut file).unwrap_or_else(|e| {
+        eprintln!("Error while loading state: {:?}", e);
+        process::exit(3)
+    })
+}
+
+fn load_engine(config: EngineConfig, state_path: Option<&Path>) -> PaymentEngine {
+    match state_path {
+        Some(path) => PaymentEngine::from_state(config, read_state_file(path)),
+        None => PaymentEngine::new(config),
+    }
+}
+
+fn save_state(engine: &PaymentEngine, path: &Path) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    state::write_state(&engine.state(), &mut file)
+}
+
+fn read_fx_rates(path: &Path) -> FxTable {
+    let mut file = File::open(path).unwrap_or_else(|e| {
+        eprintln!("Opening of FX rate file failed! Error: {:?}", e);
+        process::exit(2)
+    });
+    fx::read_rates(&mut file).unwrap_or_else(|e| {
+        eprintln!("Error while loading FX rates: {}", e);
+        process::exit(3)
+    })
+}
+
+fn read_fee_schedule(path: &Path) -> FeeSchedule {
+    let config = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("Opening of fee config failed! Error: {:?}", e);
+        process::exit(2)
+    });
+    FeeSchedule::from_toml(&config).unwrap_or_else(|e| {
+        eprintln!("Error while loading fees: {}", e);
+        process::exit(3)
+    })
+}
+
+fn read_credit_limits(path: &Path) -> CreditLimits {
+    let mut file = File::open(path).unwrap_or_else(|e| {
+        eprintln!("Opening of credit limit file failed! Error: {:?}", e);
+        process::exit(2)
+    });
+    credit::read_limits(&mut file).unwrap_or_else(|e| {
+        eprintln!("Error while loading credit limits: {}", e);
+        process::exit(3)
+    })
+}
+
+/// Settings of the consolidated report.
+struct Consolidation<'a> {
+    path: &'a Path,
+    rates: FxTable,
+    currency: String,
+    date: NaiveDate,
+}
+
+fn write_consolidated(engine: &PaymentEngine, report: &Consolidation) {
+    let outputs = fx::consolidate(engine.accounts(), &report.rates, &report.currency, report.date)
+        .unwrap_or_else(|e| {
+            eprintln!("Error while consolidating balances: {}", e);
+            process::exit(3)
+        });
+    let res = File::create(report.path)
+        .map_err(csv::Error::from)
+        .and_then(|mut file| fx::write_consolidated(&outputs, &mut file));
+    if let Err(e) = res {
+        eprintln!("Error while writing consolidated report: {:?}", e);
+        process::exit(4)
+    }
+}
+
+struct Outputs<'a> {
+    order: OutputOrder,
+    malformed: Option<&'a Path>,
+    rejections: Option<&'a Path>,
+    admin_log: Option<&'a Path>,
+    fee_log: Option<&'a Path>,
+    consolidated: Option<Consolidation<'a>>,
+    state: Option<&'a Path>,
+}
+
+fn process_file(mut file: File, mut engine: PaymentEngine, outputs: Outputs, stream: bool) {
+    if outputs.admin_log.is_some() {
+        engine.collect_admin_actions();
+    }
+    if outputs.fee_log.is_some() {
+        engine.collect_fee_items();
+    }
+    let mut malformed = Vec::new();
+    let lenient = outputs.malformed.map(|_| &mut malformed);
+    match load_transactions(&mut engine, &mut file, stream, lenient) {
+        Ok(rejections) => {
+            let accounts = engine.snapshot_ordered(outputs.order);
+            if engine.config().verbose {
                 println!("Client accounts processed: {}", accounts.len());
+                println!("Transactions rejected: {}", rejections.len());
+                if outputs.malformed.is_some() {
+                    println!("Malformed rows skipped: {}", malformed.len());
+                }
+            }
+
+            for warning in engine.warnings() {
+                eprintln!("Warning: {}", warning);
             }
 
-            let write_res = csv_handler::write_accounts(accounts, &mut io::stdout());
+            let write_res = csv_handler::write_accounts(&accounts, &mut io::stdout());
             if let Err(e) = write_res {
                 eprintln!("Error while writing output: {:?}", e);
                 process::exit(4)
             }
+
+            if let Some(path) = outputs.rejections {
+                if let Err(e) = write_rejections(&rejections, path) {
+                    eprintln!("Error while writing rejections: {:?}", e);
+                    process::exit(4)
+                }
+            }
+
+            if let Some(path) = outputs.malformed {
+                if let Err(e) = write_malformed_rows(&malformed, path) {
+                    eprintln!("Error while writing malformed rows: {:?}", e);
+                    process::exit(4)
+                }
+            }
+
+            if let Some(report) = &outputs.consolidated {
+                write_consolidated(&engine, report);
+            }
+
+            if let Some(path) = outputs.admin_log {
+                if let Err(e) = write_admin_log(&engine, path) {
+                    eprintln!("Error while writing admin log: {:?}", e);
+                    process::exit(4)
+                }
+            }
+
+            if let Some(path) = outputs.fee_log {
+                if let Err(e) = write_fee_log(&engine, path) {
+                    eprintln!("Error while writing fee log: {:?}", e);
+                    process::exit(4)
+                }
+            }
+
+            if let Some(path) = outputs.state {
+                if let Err(e) = save_state(&engine, path) {
+                    eprintln!("Error while saving state: {:?}", e);
+                    process::exit(4)
+                }
+            }
+
+            if let Some(Err(e)) = engine.take_journal().map(Journal::finish) {
+                eprintln!("Error while writing journal: {:?}", e);
+                process::exit(4)
+            }
         }
         Err(e) => {
             eprintln!("Error while loading transactions: {:?}", e);
@@ -59,17 +451,104 @@ fn process_file(mut file: File, verbose: bool) {
     }
 }
 
+fn replay_journal(config: EngineConfig, order: OutputOrder, opts: &ArgMatches) {
+    let filename = opts.value_of("JOURNAL").expect("missing journal arg"); // cannot fail here because it's a required arg
+    let mut file = File::open(filename).unwrap_or_else(|e| {
+        eprintln!("Opening of journal failed! Error: {:?}", e);
+        process::exit(2)
+    });
+
+    let engine = match journal::replay(journal::read_journal(&mut file), config) {
+        Ok(engine) => engine,
+        Err(journal::ReplayError::Io(e)) => {
+            eprintln!("Error while loading journal: {:?}", e);
+            process::exit(3)
+        }
+        Err(e) => {
+            eprintln!("Replay failed: {:?}", e);
+            process::exit(5)
+        }
+    };
+
+    if let Err(e) = csv_handler::write_accounts(&engine.snapshot_ordered(order), &mut io::stdout()) {
+        eprintln!("Error while writing output: {:?}", e);
+        process::exit(4)
+    }
+
+    if let Some(path) = opts.value_of("against").map(Path::new) {
+        let mismatches = journal::verify(&engine, &read_state_file(path));
+        if !mismatches.is_empty() {
+            eprintln!("Replayed accounts differ from the saved state for clients: {:?}", mismatches);
+            process::exit(5)
+        }
+    }
+}
+
 fn main() {
     let opts = parse_args();
 
-    let filename = opts.value_of("INPUT").expect("missing input arg"); // cannot fail here because it's a required arg
-    let verbose = opts.is_present("verbose");
+    let config = EngineConfig {
+        duplicates: value_t!(opts, "duplicates", DuplicatePolicy).unwrap_or_else(|e| e.exit()),
+        disputes: DisputeRules {
+            allow_redispute: !opts.is_present("no-redispute"),
+            withdrawals: value_t!(opts, "withdrawal-disputes", WithdrawalDisputes).unwrap_or_else(|e| e.exit()),
+            window: opts
+                .value_of("dispute-window")
+                .map(|_| Duration::days(value_t!(opts, "dispute-window", u32).unwrap_or_else(|e| e.exit()).into())),
+            prune_expired: opts.is_present("prune-expired"),
+        },
+        amounts: AmountRules {
+            scale: value_t!(opts, "scale", u32).unwrap_or_else(|e| e.exit()),
+            precision: value_t!(opts, "precision", PrecisionPolicy).unwrap_or_else(|e| e.exit()),
+        },
+        chronology: value_t!(opts, "chronology", Chronology).unwrap_or_else(|e| e.exit()),
+        fees: opts.value_of("fees").map_or_else(FeeSchedule::default, |path| read_fee_schedule(Path::new(path))),
+        credit_limits: opts
+            .value_of("credit-limits")
+            .map_or_else(CreditLimits::default, |path| read_credit_limits(Path::new(path))),
+        verbose: opts.is_present("verbose"),
+    };
+    let order = value_t!(opts, "sort", OutputOrder).unwrap_or_else(|e| e.exit());
+    if let Some(replay_opts) = opts.subcommand_matches("replay") {
+        replay_journal(config, order, replay_opts);
+        return;
+    }
+
+    let filename = opts.value_of("INPUT").expect("missing input arg"); // cannot fail here unless replaying
+    let stream = opts.is_present("stream");
+    let outputs = Outputs {
+        order,
+        malformed: opts.value_of("lenient").map(Path::new),
+        rejections: opts.value_of("rejections").map(Path::new),
+        admin_log: opts.value_of("admin-log").map(Path::new),
+        fee_log: opts.value_of("fee-log").map(Path::new),
+        consolidated: opts.value_of("consolidated").map(|path| Consolidation {
+            path: Path::new(path),
+            rates: read_fx_rates(Path::new(opts.value_of("fx-rates").expect("missing fx-rates arg"))), // required by clap
+            currency: opts.value_of("report-currency").unwrap_or("EUR").to_ascii_uppercase(),
+            date: opts.value_of("fx-date").map_or_else(
+                || DateTime::<Utc>::from(SystemTime::now()).date_naive(),
+                |_| value_t!(opts, "fx-date", NaiveDate).unwrap_or_else(|e| e.exit()),
+            ),
+        }),
+        state: opts.value_of("save-state").map(Path::new),
+    };
     let timed = opts.is_present("timed");
 
     let now = Instant::now();
 
+    let mut engine = load_engine(config, opts.value_of("load-state").map(Path::new));
+    if let Some(path) = opts.value_of("journal").map(Path::new) {
+        match Journal::open(path) {
+            Ok(journal) => engine.set_journal(journal),
+            Err(e) => {
+                eprintln!("Opening of journal failed! Error: {:?}", e);
+                process::exit(4)
+            }
+        }
+    }
     match File::open(filename) {
-        Ok(file) => process_file(file, verbose),
+        Ok(file) => process_file(file, engine, outputs, stream),
         Err(e) => {
             eprintln!("Opening of file failed! Error: {:?}", e);
             process::exit(2)
@@ -0,0 +1,230 @@
+use crate::transaction::*;
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+/// What to do with a Deposit or Withdrawal reusing a transaction ID which already belongs to another client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DuplicatePolicy {
+    Reject,
+    Warn,
+    Allow,
+}
+
+impl DuplicatePolicy {
+    pub const VALUES: [&'static str; 3] = ["reject", "warn", "allow"];
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(DuplicatePolicy::Reject),
+            "warn" => Ok(DuplicatePolicy::Warn),
+            "allow" => Ok(DuplicatePolicy::Allow),
+            _ => Err(format!("unknown duplicate policy: {}", s)),
+        }
+    }
+}
+
+/// A transaction ID reused by another client, let through by the Warn policy.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DuplicateWarning {
+    pub row: RowNumber,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    pub owner: ClientId,
+}
+
+impl fmt::Display for DuplicateWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "transaction ID {} of client {} in row {} is already used by client {}",
+            self.tx, self.client, self.row, self.owner
+        )
+    }
+}
+
+/// Engine-level record of which client owns each transaction ID.
+///
+/// IDs are claimed while routing the rows, in input order, so the outcome does not depend on
+/// how the parallel processing of the clients is scheduled.
+/// Every row which doesn't refer to an earlier transaction claims its ID, and no client can claim an ID twice,
+/// whatever the policy; the policy only decides about IDs of other clients.
+/// A claim is kept even if the account rejects the row, releasing it would make the outcome depend on the schedule again.
+#[derive(Debug)]
+pub struct TransactionRegistry {
+    policy: DuplicatePolicy,
+    owners: HashMap<TransactionId, ClientId>,
+    shared: HashSet<(TransactionId, ClientId)>, // further clients of an ID, let through by Warn or Allow
+}
+
+impl TransactionRegistry {
+    pub fn new(policy: DuplicatePolicy) -> TransactionRegistry {
+        TransactionRegistry::with_owners(policy, HashMap::new(), HashSet::new())
+    }
+
+    /// Continues with the IDs claimed in a previous run.
+    pub fn with_owners(
+        policy: DuplicatePolicy,
+        owners: HashMap<TransactionId, ClientId>,
+        shared: HashSet<(TransactionId, ClientId)>,
+    ) -> TransactionRegistry {
+        TransactionRegistry { policy, owners, shared }
+    }
+
+    pub fn owners(&self) -> &HashMap<TransactionId, ClientId> {
+        &self.owners
+    }
+
+    /// IDs used by a client besides their owner.
+    pub fn shared(&self) -> &HashSet<(TransactionId, ClientId)> {
+        &self.shared
+    }
+
+    /// Whether the client has claimed the ID, as its owner or with the consent of the policy.
+    pub fn is_used_by(&self, transaction_id: TransactionId, client: ClientId) -> bool {
+        self.owners.get(&transaction_id) == Some(&client) || self.shared.contains(&(transaction_id, client))
+    }
+
+    /// Claims the ID of the transaction for its client. The warning is returned when the Warn policy
+    /// lets a transaction reuse the ID of another client, printing it is left to the caller.
+    pub fn claim(&mut self, row: RowNumber, tr: &Transaction) -> Result<Option<DuplicateWarning>> {
+        if !tr.transaction_type.creates_transaction_id() {
+            return Ok(None);
+        }
+
+        if self.is_used_by(tr.transaction_id, tr.client_id) {
+            return Err(Error::DuplicatedTransactionId);
+        }
+        let key = (tr.transaction_id, tr.client_id);
+        match self.owners.entry(tr.transaction_id) {
+            Entry::Vacant(e) => {
+                e.insert(tr.client_id);
+                Ok(None)
+            }
+            Entry::Occupied(e) => match self.policy {
+                DuplicatePolicy::Reject => Err(Error::DuplicatedTransactionId),
+                DuplicatePolicy::Warn => {
+                    let owner = *e.get();
+                    self.shared.insert(key);
+                    Ok(Some(DuplicateWarning {
+                        row,
+                        client: tr.client_id,
+                        tx: tr.transaction_id,
+                        owner,
+                    }))
+                }
+                DuplicatePolicy::Allow => {
+                    self.shared.insert(key);
+                    Ok(None)
+                }
+            },
+        }
+    }
+}
+
+impl Default for TransactionRegistry {
+    fn default() -> Self {
+        TransactionRegistry::new(DuplicatePolicy::Reject)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    fn deposit(client_id: ClientId, transaction_id: TransactionId) -> Transaction {
+        Transaction {
+            transaction_type: TransactionType::Deposit,
+            client_id,
+            transaction_id,
+            amount: Some(Decimal::new(100, 0)),
+            reason: None,
+            currency: None,
+            timestamp: None,
+            recipient: None,
+        }
+    }
+
+    #[test]
+    fn test_reject() {
+        let mut registry = TransactionRegistry::new(DuplicatePolicy::Reject);
+        assert_eq!(registry.claim(0, &deposit(1, 1)), Ok(None));
+        assert_eq!(
+            registry.claim(0, &deposit(2, 1)),
+            Err(Error::DuplicatedTransactionId)
+        );
+        assert_eq!(registry.claim(0, &deposit(2, 2)), Ok(None));
+    }
+
+    #[test]
+    fn test_same_client_is_rejected() {
+        for policy in [DuplicatePolicy::Reject, DuplicatePolicy::Warn, DuplicatePolicy::Allow].iter() {
+            let mut registry = TransactionRegistry::new(*policy);
+            assert_eq!(registry.claim(0, &deposit(1, 1)), Ok(None));
+            assert_eq!(
+                registry.claim(0, &deposit(1, 1)),
+                Err(Error::DuplicatedTransactionId),
+                "{:?}",
+                policy
+            );
+
+            let mut transfer = deposit(1, 1);
+            transfer.transaction_type = TransactionType::Transfer;
+            assert_eq!(registry.claim(0, &transfer), Err(Error::DuplicatedTransactionId), "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn test_references_are_not_claims() {
+        let mut registry = TransactionRegistry::new(DuplicatePolicy::Reject);
+        assert_eq!(registry.claim(0, &deposit(1, 1)), Ok(None));
+
+        let dispute = Transaction {
+            transaction_type: TransactionType::Dispute,
+            client_id: 2,
+            transaction_id: 1,
+            amount: None,
+            reason: None,
+            currency: None,
+            timestamp: None,
+            recipient: None,
+        };
+        assert_eq!(registry.claim(0, &dispute), Ok(None));
+    }
+
+    #[test]
+    fn test_warn_and_allow() {
+        let mut registry = TransactionRegistry::new(DuplicatePolicy::Warn);
+        assert_eq!(registry.claim(2, &deposit(1, 1)), Ok(None));
+        assert_eq!(
+            registry.claim(3, &deposit(2, 1)),
+            Ok(Some(DuplicateWarning {
+                row: 3,
+                client: 2,
+                tx: 1,
+                owner: 1
+            }))
+        );
+
+        assert_eq!(
+            registry.claim(4, &deposit(2, 1)),
+            Err(Error::DuplicatedTransactionId),
+            "client 2 already uses the ID"
+        );
+
+        let mut registry = TransactionRegistry::new(DuplicatePolicy::Allow);
+        assert_eq!(registry.claim(2, &deposit(1, 1)), Ok(None));
+        assert_eq!(registry.claim(3, &deposit(2, 1)), Ok(None));
+        assert!(registry.is_used_by(1, 1) && registry.is_used_by(1, 2));
+        assert!(!registry.is_used_by(1, 3));
+        assert_eq!(registry.claim(4, &deposit(2, 1)), Err(Error::DuplicatedTransactionId));
+        assert_eq!(registry.claim(5, &deposit(1, 1)), Err(Error::DuplicatedTransactionId));
+    }
+}
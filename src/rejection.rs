@@ -0,0 +1,143 @@
+use crate::transaction::*;
+
+use serde::Serialize;
+use std::io;
+use std::path::Path;
+
+/// A transaction which was skipped during processing, with the reason of the rejection.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Rejection {
+    pub row: RowNumber,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+    pub error: Error,
+}
+
+impl Rejection {
+    pub fn new(row: RowNumber, tr: &Transaction, error: Error) -> Rejection {
+        Rejection {
+            row,
+            client: tr.client_id,
+            tx: tr.transaction_id,
+            transaction_type: tr.transaction_type.clone(),
+            error,
+        }
+    }
+}
+
+/// Logs the rejection if verbose, then adds it to the list.
+pub(crate) fn reject(
+    rejections: &mut Vec<Rejection>,
+    row: RowNumber,
+    tr: &Transaction,
+    error: Error,
+    verbose: bool,
+) {
+    if verbose {
+        println!(
+            "Ignoring transaction with ID: {}. Reason: {:?}",
+            tr.transaction_id, error
+        )
+    }
+    rejections.push(Rejection::new(row, tr, error));
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReportFormat {
+    Csv,
+    Json,
+}
+
+impl ReportFormat {
+    /// JSON for files with a `.json` extension, CSV for everything else.
+    pub fn from_path(path: &Path) -> ReportFormat {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => ReportFormat::Json,
+            _ => ReportFormat::Csv,
+        }
+    }
+}
+
+pub fn write_rejections(
+    rejections: &[Rejection],
+    format: ReportFormat,
+    output: &mut dyn io::Write,
+) -> io::Result<()> {
+    match format {
+        ReportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(output);
+            for rejection in rejections {
+                writer.serialize(rejection)?;
+            }
+            writer.flush()?;
+        }
+        ReportFormat::Json => {
+            serde_json::to_writer_pretty(&mut *output, rejections)?;
+            writeln!(output)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    fn rejection() -> Rejection {
+        let tr = Transaction {
+            transaction_type: TransactionType::Withdrawal,
+            client_id: 2,
+            transaction_id: 5,
+            amount: Some(Decimal::new(3, 0)),
+            reason: None,
+            currency: None,
+            timestamp: None,
+            recipient: None,
+        };
+        Rejection::new(6, &tr, Error::InsufficientFunds)
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(
+            ReportFormat::from_path(Path::new("out/rejected.json")),
+            ReportFormat::Json
+        );
+        assert_eq!(
+            ReportFormat::from_path(Path::new("rejected.JSON")),
+            ReportFormat::Json
+        );
+        assert_eq!(
+            ReportFormat::from_path(Path::new("rejected.csv")),
+            ReportFormat::Csv
+        );
+        assert_eq!(
+            ReportFormat::from_path(Path::new("rejected")),
+            ReportFormat::Csv
+        );
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let mut out = Vec::new();
+        write_rejections(&[rejection()], ReportFormat::Csv, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "row,client,tx,type,error\n6,2,5,withdrawal,InsufficientFunds\n"
+        );
+    }
+
+    #[test]
+    fn test_write_json() {
+        let mut out = Vec::new();
+        write_rejections(&[rejection()], ReportFormat::Json, &mut out).unwrap();
+        let parsed: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            parsed,
+            serde_json::json!([{"row": 6, "client": 2, "tx": 5, "type": "withdrawal", "error": "InsufficientFunds"}])
+        );
+    }
+}
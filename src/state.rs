@@ -0,0 +1,123 @@
+use crate::account::Account;
+use crate::transaction::*;
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+/// Everything the engine needs to continue in a later run: balances, lock flags, the dispute status
+/// of every past Deposit and Withdrawal, and the owners of the used transaction IDs.
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct EngineState {
+    pub accounts: Vec<Account>,
+    pub transaction_owners: HashMap<TransactionId, ClientId>,
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub shared_transaction_ids: HashSet<(TransactionId, ClientId)>, // IDs reused by other clients under Warn or Allow
+}
+
+pub fn write_state(state: &EngineState, output: &mut dyn io::Write) -> io::Result<()> {
+    serde_json::to_writer(&mut *output, state)?;
+    writeln!(output)
+}
+
+pub fn read_state(input: &mut dyn io::Read) -> io::Result<EngineState> {
+    Ok(serde_json::from_reader(input)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{EngineConfig, PaymentEngine};
+    use crate::registry::DuplicatePolicy;
+    use rust_decimal::Decimal;
+
+    fn transaction(
+        transaction_type: TransactionType,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        amount: Option<Decimal>,
+    ) -> Transaction {
+        Transaction {
+            transaction_type,
+            client_id,
+            transaction_id,
+            amount,
+            reason: None,
+            currency: None,
+            timestamp: None,
+            recipient: None,
+        }
+    }
+
+    fn save_and_restore(engine: &PaymentEngine) -> PaymentEngine {
+        let mut out = Vec::new();
+        write_state(&engine.state(), &mut out).expect("saving state failed");
+        let state = read_state(&mut out.as_slice()).expect("loading state failed");
+        PaymentEngine::from_state(engine.config().clone(), state)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut engine = PaymentEngine::default();
+        let day_1 = [
+            transaction(TransactionType::Deposit, 1, 1, Some(Decimal::new(123456, 4))),
+            transaction(TransactionType::Deposit, 2, 2, Some(Decimal::new(50, 0))),
+            transaction(TransactionType::Dispute, 2, 2, None),
+            transaction(TransactionType::Chargeback, 2, 2, None),
+        ];
+        for tr in day_1.iter() {
+            engine.apply(tr).expect("day 1 failed");
+        }
+
+        let restored = save_and_restore(&engine);
+        assert_eq!(restored.state(), engine.state());
+        assert_eq!(restored.account(2).map(|a| a.is_locked()), Some(true));
+    }
+
+    #[test]
+    fn test_dispute_after_restore() {
+        let mut engine = PaymentEngine::default();
+        engine
+            .apply(&transaction(TransactionType::Deposit, 1, 1, Some(Decimal::new(100, 0))))
+            .unwrap();
+
+        let mut engine = save_and_restore(&engine);
+        let dispute = transaction(TransactionType::Dispute, 1, 1, None);
+        assert_eq!(engine.apply(&dispute), Ok(()));
+
+        let acc = engine.account(1).unwrap();
+        assert_eq!(acc.available(), Decimal::ZERO);
+        assert_eq!(acc.held(), Decimal::new(100, 0));
+    }
+
+    #[test]
+    fn test_duplicate_after_restore() {
+        let mut engine = PaymentEngine::default();
+        engine
+            .apply(&transaction(TransactionType::Deposit, 1, 1, Some(Decimal::new(100, 0))))
+            .unwrap();
+
+        let mut engine = save_and_restore(&engine);
+        let reused = transaction(TransactionType::Deposit, 2, 1, Some(Decimal::new(5, 0)));
+        assert_eq!(engine.apply(&reused), Err(Error::DuplicatedTransactionId));
+    }
+
+    #[test]
+    fn test_shared_id_after_restore() {
+        let config = EngineConfig {
+            duplicates: DuplicatePolicy::Allow,
+            ..EngineConfig::default()
+        };
+        let mut engine = PaymentEngine::new(config);
+        engine
+            .apply(&transaction(TransactionType::Deposit, 1, 1, Some(Decimal::new(100, 0))))
+            .unwrap();
+        engine.apply(&transaction(TransactionType::Freeze, 2, 1, None)).unwrap();
+
+        let mut engine = save_and_restore(&engine);
+        assert_eq!(
+            engine.apply(&transaction(TransactionType::Unlock, 2, 1, None)),
+            Err(Error::DuplicatedTransactionId)
+        );
+    }
+}
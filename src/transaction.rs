@@ -1,10 +1,21 @@
+use chrono::{DateTime, Utc};
 use rust_decimal::Decimal;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::fmt;
 
 pub type ClientId = u16;
 pub type TransactionId = u32;
+pub type RowNumber = u64; // line number in the input file
+pub type Currency = String;
+pub type Timestamp = DateTime<Utc>;
 
-#[derive(Debug, PartialEq)]
+/// Currency of the transactions which don't have one, like the rows of inputs without a currency column.
+pub const DEFAULT_CURRENCY: &str = "EUR";
+
+/// A transaction together with the input line it was read from.
+pub type Row = (RowNumber, Transaction);
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
 pub enum Error {
     MissingAmount,
     InsufficientFunds,
@@ -14,11 +25,69 @@ pub enum Error {
     DuplicatedTransactionId,
     AlreadyDisputed,
     NotDisputed,
+    AlreadyResolved,
+    AlreadyChargedBack,
+    WithdrawalDisputeForbidden,
+    AccountClosed,
+    AccountNotEmpty,
+    MissingReason,
+    BalanceOverflow,
+    NegativeAmount,
+    ZeroAmount,
+    PrecisionExceeded,
+    InvalidCurrency,
+    CurrencyMismatch,
+    OutOfOrder,
+    MissingTimestamp,
+    DisputeWindowExpired,
+    DisputedAmountExceeded,
+    RefundedAmountExceeded,
+    AlreadyReversed,
+    InvalidRecipient,
+    CreditLimitExceeded,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            Error::MissingAmount => "missing amount",
+            Error::InsufficientFunds => "insufficient funds",
+            Error::ClientIdMismatch => "transaction belongs to another client",
+            Error::AccountLocked => "account is locked",
+            Error::UnknownTransactionId => "unknown transaction ID",
+            Error::DuplicatedTransactionId => "duplicated transaction ID",
+            Error::AlreadyDisputed => "transaction is already disputed",
+            Error::NotDisputed => "transaction is not disputed",
+            Error::AlreadyResolved => "resolved transaction cannot be disputed again",
+            Error::AlreadyChargedBack => "transaction is already charged back",
+            Error::WithdrawalDisputeForbidden => "withdrawals cannot be disputed",
+            Error::AccountClosed => "account is closed",
+            Error::AccountNotEmpty => "account with funds cannot be closed",
+            Error::MissingReason => "missing reason code",
+            Error::BalanceOverflow => "balance would exceed the supported range",
+            Error::NegativeAmount => "amount is negative",
+            Error::ZeroAmount => "amount is zero",
+            Error::PrecisionExceeded => "amount has more decimal places than allowed",
+            Error::InvalidCurrency => "currency is not a three-letter code",
+            Error::CurrencyMismatch => "currency differs from the disputed transaction",
+            Error::OutOfOrder => "transaction is older than the previous one of the client",
+            Error::MissingTimestamp => "missing timestamp",
+            Error::DisputeWindowExpired => "dispute window of the transaction has expired",
+            Error::DisputedAmountExceeded => "disputed amount exceeds the amount of the transaction",
+            Error::RefundedAmountExceeded => "refunded amount exceeds the amount of the transaction",
+            Error::AlreadyReversed => "transaction is already reversed",
+            Error::InvalidRecipient => "transfer needs a recipient other than the sender",
+            Error::CreditLimitExceeded => "transaction or its fee exceeds the credit limit",
+        };
+        f.write_str(msg)
+    }
 }
 
+impl std::error::Error for Error {}
+
 pub type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum TransactionType {
     Deposit,
@@ -26,9 +95,30 @@ pub enum TransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    Refund,
+    Reversal,
+    Transfer,
+    // administrative operations of the support staff
+    Unlock,
+    Freeze,
+    Close,
+    Adjust,
+}
+
+impl TransactionType {
+    /// Disputes, Resolves, Chargebacks, Refunds and Reversals refer to an existing transaction, the other types have their own ID.
+    pub fn creates_transaction_id(&self) -> bool {
+        use TransactionType::*;
+        !matches!(self, Dispute | Resolve | Chargeback | Refund | Reversal)
+    }
+
+    pub fn is_admin(&self) -> bool {
+        use TransactionType::*;
+        matches!(self, Unlock | Freeze | Close | Adjust)
+    }
 }
 
-#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Transaction {
     #[serde(rename = "type")]
     pub transaction_type: TransactionType,
@@ -37,6 +127,14 @@ pub struct Transaction {
     #[serde(rename = "tx")]
     pub transaction_id: TransactionId,
     pub amount: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>, // reason code of administrative operations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+    #[serde(rename = "to", default, skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<ClientId>, // receiving client of a transfer
 }
 
 impl Transaction {
@@ -44,6 +142,17 @@ impl Transaction {
         self.amount.ok_or(Error::MissingAmount)
     }
 
+    pub fn currency(&self) -> &str {
+        self.currency.as_deref().unwrap_or(DEFAULT_CURRENCY)
+    }
+
+    pub fn get_reason(&self) -> Result<&str> {
+        match self.reason.as_deref() {
+            Some(reason) if !reason.is_empty() => Ok(reason),
+            _ => Err(Error::MissingReason),
+        }
+    }
+
     pub fn check_amount_empty(&self, verbose: bool) {
         if verbose && self.amount.is_some() {
             println!(
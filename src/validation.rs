@@ -0,0 +1,219 @@
+use crate::transaction::*;
+
+use rust_decimal::RoundingStrategy;
+use std::str::FromStr;
+
+/// What to do with an amount which has more decimal places than allowed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PrecisionPolicy {
+    Reject,
+    Round,
+}
+
+impl PrecisionPolicy {
+    pub const VALUES: [&'static str; 2] = ["reject", "round"];
+}
+
+impl FromStr for PrecisionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(PrecisionPolicy::Reject),
+            "round" => Ok(PrecisionPolicy::Round),
+            _ => Err(format!("unknown precision policy: {}", s)),
+        }
+    }
+}
+
+/// Checks of the transaction amounts, done before a transaction reaches the accounts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AmountRules {
+    pub scale: u32, // maximum number of decimal places
+    pub precision: PrecisionPolicy,
+}
+
+impl Default for AmountRules {
+    fn default() -> Self {
+        AmountRules {
+            scale: 4,
+            precision: PrecisionPolicy::Reject,
+        }
+    }
+}
+
+impl AmountRules {
+    /// Validates the amount of Deposits, Withdrawals, Disputes, Refunds, Transfers and Adjustments, rounding it if the policy allows.
+    /// Adjustments may be negative, the amounts of other transaction types are not used.
+    pub fn validate(&self, tr: &mut Transaction) -> Result<()> {
+        let signed = match tr.transaction_type {
+            TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Dispute
+            | TransactionType::Refund
+            | TransactionType::Transfer => false,
+            TransactionType::Adjust => true,
+            _ => return Ok(()),
+        };
+        let mut amount = match tr.amount {
+            Some(amount) => amount,
+            None => return Ok(()), // left for the account to reject
+        };
+
+        // trailing zeros are not significant decimal places
+        if amount.normalize().scale() > self.scale {
+            match self.precision {
+                PrecisionPolicy::Reject => return Err(Error::PrecisionExceeded),
+                PrecisionPolicy::Round => {
+                    amount = amount
+                        .round_dp_with_strategy(self.scale, RoundingStrategy::MidpointAwayFromZero)
+                }
+            }
+        }
+        if amount.is_zero() {
+            return Err(Error::ZeroAmount);
+        }
+        if amount.is_sign_negative() && !signed {
+            return Err(Error::NegativeAmount);
+        }
+
+        tr.amount = Some(amount);
+        Ok(())
+    }
+}
+
+/// Currencies are three-letter codes, stored in upper case.
+pub fn validate_currency(currency: &mut Option<Currency>) -> Result<()> {
+    if let Some(currency) = currency.as_mut() {
+        if currency.len() != 3 || !currency.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(Error::InvalidCurrency);
+        }
+        currency.make_ascii_uppercase();
+    }
+    Ok(())
+}
+
+/// Transfers need a recipient other than the sender, other transaction types must not have one.
+pub fn validate_recipient(tr: &Transaction) -> Result<()> {
+    let transfer = tr.transaction_type == TransactionType::Transfer;
+    match tr.recipient {
+        Some(recipient) if transfer && recipient != tr.client_id => Ok(()),
+        None if !transfer => Ok(()),
+        _ => Err(Error::InvalidRecipient),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    fn transaction(transaction_type: TransactionType, amount: Decimal) -> Transaction {
+        Transaction {
+            transaction_type,
+            client_id: 1,
+            transaction_id: 1,
+            amount: Some(amount),
+            reason: None,
+            currency: None,
+            timestamp: None,
+            recipient: None,
+        }
+    }
+
+    #[test]
+    fn test_negative_and_zero() {
+        let rules = AmountRules::default();
+        let mut deposit = transaction(TransactionType::Deposit, Decimal::new(-5, 0));
+        assert_eq!(rules.validate(&mut deposit), Err(Error::NegativeAmount));
+
+        let mut withdrawal = transaction(TransactionType::Withdrawal, Decimal::new(0, 2));
+        assert_eq!(rules.validate(&mut withdrawal), Err(Error::ZeroAmount));
+
+        let mut adjust = transaction(TransactionType::Adjust, Decimal::new(-5, 0));
+        assert_eq!(
+            rules.validate(&mut adjust),
+            Ok(()),
+            "adjustments can be negative"
+        );
+
+        let mut dispute = transaction(TransactionType::Dispute, Decimal::new(-5, 0));
+        assert_eq!(rules.validate(&mut dispute), Err(Error::NegativeAmount));
+
+        let mut refund = transaction(TransactionType::Refund, Decimal::new(-5, 0));
+        assert_eq!(rules.validate(&mut refund), Err(Error::NegativeAmount));
+
+        let mut resolve = transaction(TransactionType::Resolve, Decimal::new(-5, 0));
+        assert_eq!(
+            rules.validate(&mut resolve),
+            Ok(()),
+            "amount of a resolve is not used"
+        );
+    }
+
+    #[test]
+    fn test_precision_reject() {
+        let rules = AmountRules::default();
+        let mut valid = transaction(TransactionType::Deposit, Decimal::new(12345, 4));
+        assert_eq!(rules.validate(&mut valid), Ok(()));
+        assert_eq!(valid.amount, Some(Decimal::new(12345, 4)));
+
+        let mut precise = transaction(TransactionType::Deposit, Decimal::new(123456, 5));
+        assert_eq!(rules.validate(&mut precise), Err(Error::PrecisionExceeded));
+
+        let mut trailing_zeros = transaction(TransactionType::Deposit, "1.00000".parse().unwrap());
+        assert_eq!(rules.validate(&mut trailing_zeros), Ok(()), "1.00000 has no decimal places");
+        assert_eq!(trailing_zeros.amount, Some(Decimal::ONE));
+    }
+
+    #[test]
+    fn test_precision_round() {
+        let rules = AmountRules {
+            scale: 2,
+            precision: PrecisionPolicy::Round,
+        };
+        let mut precise = transaction(TransactionType::Deposit, Decimal::new(12345, 4));
+        assert_eq!(rules.validate(&mut precise), Ok(()));
+        assert_eq!(precise.amount, Some(Decimal::new(123, 2)));
+
+        let mut tiny = transaction(TransactionType::Withdrawal, Decimal::new(4, 3));
+        assert_eq!(
+            rules.validate(&mut tiny),
+            Err(Error::ZeroAmount),
+            "rounded to zero"
+        );
+    }
+
+    #[test]
+    fn test_currency() {
+        let mut tr = transaction(TransactionType::Deposit, Decimal::ONE);
+        assert_eq!(validate_currency(&mut tr.currency), Ok(()), "currency is optional");
+
+        tr.currency = Some("usd".to_string());
+        assert_eq!(validate_currency(&mut tr.currency), Ok(()));
+        assert_eq!(tr.currency.as_deref(), Some("USD"));
+
+        for invalid in ["US", "USDT", "U$D"].iter() {
+            tr.currency = Some(invalid.to_string());
+            assert_eq!(
+                validate_currency(&mut tr.currency),
+                Err(Error::InvalidCurrency),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_recipient() {
+        let mut tr = transaction(TransactionType::Transfer, Decimal::ONE);
+        assert_eq!(validate_recipient(&tr), Err(Error::InvalidRecipient));
+        tr.recipient = Some(1);
+        assert_eq!(validate_recipient(&tr), Err(Error::InvalidRecipient), "transfer to the sender");
+        tr.recipient = Some(2);
+        assert_eq!(validate_recipient(&tr), Ok(()));
+
+        tr.transaction_type = TransactionType::Deposit;
+        assert_eq!(validate_recipient(&tr), Err(Error::InvalidRecipient));
+    }
+}
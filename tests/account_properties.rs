@@ -0,0 +1,266 @@
+//! Randomized sequences of customer transactions applied to a single `Account`, cross-checking the
+//! balances and the total against a simple reference model after every step. A locked account must not
+//! change, a resolve must undo its disputes, and the held funds must not be negative unless the
+//! withdrawal disputes are symmetric, where a disputed withdrawal holds a negative amount.
+
+use payment_engine::account::{Account, DisputeRules, WithdrawalDisputes};
+use payment_engine::{Transaction, TransactionId, TransactionType};
+
+use proptest::prelude::*;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+
+const CLIENT: u16 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Settled,
+    Disputed,
+    Resolved,
+    ChargedBack,
+    Reversed,
+}
+
+struct ModelTransaction {
+    deposit: bool,
+    amount: Decimal,
+    disputed: Decimal,
+    refunded: Decimal,
+    state: State,
+}
+
+/// Straightforward re-implementation of the balance rules, kept independent from `Account`.
+struct Model {
+    rules: DisputeRules,
+    available: Decimal,
+    held: Decimal,
+    /// Running total, only changed by the transactions which move funds in or out of the account.
+    total: Decimal,
+    locked: bool,
+    transactions: HashMap<TransactionId, ModelTransaction>,
+}
+
+impl Model {
+    fn new(rules: DisputeRules) -> Model {
+        Model {
+            rules,
+            available: Decimal::ZERO,
+            held: Decimal::ZERO,
+            total: Decimal::ZERO,
+            locked: false,
+            transactions: HashMap::new(),
+        }
+    }
+
+    /// Signed effect of a disputed portion of the transaction on the balance, and the part of it held by the dispute.
+    fn amounts(&self, tr: &ModelTransaction, portion: Decimal) -> (Decimal, Decimal) {
+        if tr.deposit {
+            return (portion, portion);
+        }
+        let held = match self.rules.withdrawals {
+            WithdrawalDisputes::Symmetric => -portion,
+            _ => Decimal::ZERO,
+        };
+        (-portion, held)
+    }
+
+    /// Applies the transaction, returning whether it was accepted.
+    fn apply(&mut self, tr: &Transaction) -> bool {
+        if self.locked {
+            return false;
+        }
+        let id = tr.transaction_id;
+
+        match tr.transaction_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => {
+                let deposit = tr.transaction_type == TransactionType::Deposit;
+                let amount = tr.amount.unwrap();
+                if self.transactions.contains_key(&id) || (!deposit && self.available < amount) {
+                    return false;
+                }
+                let signed = if deposit { amount } else { -amount };
+                self.available += signed;
+                self.total += signed;
+                self.transactions.insert(
+                    id,
+                    ModelTransaction {
+                        deposit,
+                        amount,
+                        disputed: Decimal::ZERO,
+                        refunded: Decimal::ZERO,
+                        state: State::Settled,
+                    },
+                );
+            }
+            TransactionType::Dispute => {
+                let t = match self.transactions.get(&id) {
+                    Some(t) => t,
+                    None => return false,
+                };
+                // a partial dispute adds to an open one
+                let allowed = match (t.state, tr.amount) {
+                    (State::Disputed, Some(_)) => true,
+                    (State::Settled, _) => true,
+                    (State::Resolved, _) => self.rules.allow_redispute,
+                    _ => false,
+                };
+                let portion = tr.amount.unwrap_or(t.amount - t.refunded);
+                if !allowed
+                    || portion.is_zero()
+                    || t.disputed + portion > t.amount - t.refunded
+                    || (!t.deposit && self.rules.withdrawals == WithdrawalDisputes::Forbid)
+                {
+                    return false;
+                }
+                let (_, held) = self.amounts(t, portion);
+                self.available -= held;
+                self.held += held;
+                let t = self.transactions.get_mut(&id).unwrap();
+                t.disputed += portion;
+                t.state = State::Disputed;
+            }
+            TransactionType::Resolve | TransactionType::Chargeback => {
+                match self.transactions.get(&id) {
+                    Some(t) if t.state == State::Disputed => {}
+                    _ => return false,
+                }
+                let t = &self.transactions[&id];
+                let (signed, held) = self.amounts(t, t.disputed);
+                self.held -= held;
+                let state = if tr.transaction_type == TransactionType::Resolve {
+                    self.available += held;
+                    State::Resolved
+                } else {
+                    self.available -= signed - held;
+                    self.total -= signed;
+                    self.locked = true;
+                    State::ChargedBack
+                };
+                let t = self.transactions.get_mut(&id).unwrap();
+                t.disputed = Decimal::ZERO;
+                t.state = state;
+            }
+            TransactionType::Refund | TransactionType::Reversal => {
+                let t = match self.transactions.get(&id) {
+                    Some(t) if t.state == State::Settled || t.state == State::Resolved => t,
+                    _ => return false,
+                };
+                let refund = tr.transaction_type == TransactionType::Refund;
+                let portion = match tr.amount {
+                    Some(amount) if refund => amount,
+                    _ => t.amount - t.refunded,
+                };
+                if refund && (portion.is_zero() || t.refunded + portion > t.amount) {
+                    return false;
+                }
+                let signed = if t.deposit { portion } else { -portion };
+                if signed > Decimal::ZERO && self.available < signed {
+                    return false;
+                }
+                self.available -= signed;
+                self.total -= signed;
+                let t = self.transactions.get_mut(&id).unwrap();
+                if refund {
+                    t.refunded += portion;
+                } else {
+                    t.state = State::Reversed;
+                }
+            }
+            _ => unreachable!("only customer transactions are generated"),
+        }
+        true
+    }
+}
+
+fn transaction() -> impl Strategy<Value = Transaction> {
+    let amount = (1i64..100_000).prop_map(|cents| Some(Decimal::new(cents, 2)));
+    let tx = 1u32..8;
+    prop_oneof![
+        (tx.clone(), amount.clone()).prop_map(|(tx, amount)| (
+            TransactionType::Deposit,
+            tx,
+            amount
+        )),
+        (tx.clone(), amount.clone()).prop_map(|(tx, amount)| (TransactionType::Withdrawal, tx, amount)),
+        (tx.clone(), prop::option::of(amount.clone()))
+            .prop_map(|(tx, amount)| (TransactionType::Dispute, tx, amount.flatten())),
+        tx.clone()
+            .prop_map(|tx| (TransactionType::Resolve, tx, None)),
+        tx.clone()
+            .prop_map(|tx| (TransactionType::Chargeback, tx, None)),
+        (tx.clone(), prop::option::of(amount))
+            .prop_map(|(tx, amount)| (TransactionType::Refund, tx, amount.flatten())),
+        tx.prop_map(|tx| (TransactionType::Reversal, tx, None)),
+    ]
+    .prop_map(|(transaction_type, transaction_id, amount)| Transaction {
+        transaction_type,
+        client_id: CLIENT,
+        transaction_id,
+        amount,
+        reason: None,
+        currency: None,
+        timestamp: None,
+        recipient: None,
+    })
+}
+
+fn rules() -> impl Strategy<Value = DisputeRules> {
+    let withdrawals = prop_oneof![
+        Just(WithdrawalDisputes::Forbid),
+        Just(WithdrawalDisputes::Symmetric),
+        Just(WithdrawalDisputes::HoldWithoutCredit),
+    ];
+    (any::<bool>(), withdrawals).prop_map(|(allow_redispute, withdrawals)| DisputeRules {
+        allow_redispute,
+        withdrawals,
+        ..DisputeRules::default()
+    })
+}
+
+proptest! {
+    #[test]
+    fn test_invariants_and_model(
+        rules in rules(),
+        transactions in prop::collection::vec(transaction(), 1..60),
+    ) {
+        let mut acc = Account::new(CLIENT);
+        let mut model = Model::new(rules.clone());
+        let mut dispute_changes: HashMap<TransactionId, (Decimal, Decimal)> = HashMap::new();
+
+        for tr in &transactions {
+            let before = acc.clone();
+            let accepted = acc.process_with(tr, &rules, false).is_ok();
+
+            prop_assert_eq!(accepted, model.apply(tr), "accepted differs at {:?}", tr);
+            prop_assert_eq!((acc.available(), acc.held()), (model.available, model.held), "balances differ after {:?}", tr);
+            prop_assert_eq!(acc.is_locked(), model.locked);
+
+            prop_assert_eq!(acc.total(), model.total, "total differs after {:?}", tr);
+            // a disputed withdrawal holds a negative amount under the symmetric policy
+            if rules.withdrawals != WithdrawalDisputes::Symmetric {
+                prop_assert!(!acc.held().is_sign_negative(), "negative held after {:?}", tr);
+            }
+            if before.is_locked() {
+                prop_assert_eq!(&acc, &before, "locked account changed by {:?}", tr);
+            }
+
+            if accepted {
+                let change = (acc.available() - before.available(), acc.held() - before.held());
+                match tr.transaction_type {
+                    TransactionType::Dispute => {
+                        let total = dispute_changes.entry(tr.transaction_id).or_default();
+                        *total = (total.0 + change.0, total.1 + change.1);
+                    }
+                    TransactionType::Resolve => {
+                        let (available, held) = dispute_changes.remove(&tr.transaction_id).unwrap();
+                        prop_assert_eq!(change, (-available, -held), "resolve did not undo the disputes");
+                    }
+                    TransactionType::Chargeback => {
+                        dispute_changes.remove(&tr.transaction_id);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,184 @@
+//! End-to-end tests running the binary on the fixtures in `test/`, comparing the account output
+//! and the rejection report with the checked-in files in `test/expected/`.
+
+mod common;
+
+use common::{expected, fixture_file, test_file, FIXTURES};
+use std::fs;
+use std::process::{Command, Output};
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+        .args(args)
+        .output()
+        .expect("running the binary failed")
+}
+
+fn run_fixture(fixture: &str, extra_args: &[&str]) {
+    let dir = tempfile::tempdir().unwrap();
+    let rejections = dir.path().join("rejections.csv");
+    let input = fixture_file(fixture);
+
+    let mut args = vec!["-r", rejections.to_str().unwrap()];
+    args.extend_from_slice(extra_args);
+    args.push(input.to_str().unwrap());
+    let output = run(&args);
+
+    assert_eq!(output.status.code(), Some(0), "{}: {:?}", fixture, output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        expected(&format!("{}.csv", fixture)),
+        "accounts of {}",
+        fixture
+    );
+    assert_eq!(
+        fs::read_to_string(&rejections).unwrap(),
+        expected(&format!("{}_rejections.csv", fixture)),
+        "rejections of {}",
+        fixture
+    );
+}
+
+#[test]
+fn test_fixtures() {
+    for fixture in FIXTURES.iter() {
+        run_fixture(fixture, &[]);
+    }
+}
+
+#[test]
+fn test_fixtures_streamed() {
+    for fixture in FIXTURES.iter() {
+        run_fixture(fixture, &["-s"]);
+    }
+}
+
+#[test]
+fn test_missing_input_exits_with_2() {
+    let output = run(&[test_file("no_such_file.csv").to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn test_invalid_input_exits_with_3() {
+    let input = test_file("invalid_type.csv");
+    for mode in [None, Some("-s")].iter() {
+        let mut args: Vec<&str> = mode.iter().copied().collect();
+        args.push(input.to_str().unwrap());
+        let output = run(&args);
+        assert_eq!(output.status.code(), Some(3), "mode: {:?}", mode);
+    }
+}
+
+#[test]
+fn test_lenient_skips_malformed_rows() {
+    let dir = tempfile::tempdir().unwrap();
+    let malformed = dir.path().join("malformed.csv");
+    let input = test_file("invalid_type.csv");
+    for mode in [None, Some("-s")].iter() {
+        let mut args: Vec<&str> = mode.iter().copied().collect();
+        args.extend_from_slice(&[
+            "--lenient",
+            malformed.to_str().unwrap(),
+            input.to_str().unwrap(),
+        ]);
+        let output = run(&args);
+
+        assert_eq!(output.status.code(), Some(0), "mode: {:?}", mode);
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            expected("invalid_type_lenient.csv"),
+            "mode: {:?}",
+            mode
+        );
+        let report = fs::read_to_string(&malformed).unwrap();
+        assert!(report.starts_with("row,error\n3,"), "report: {}", report);
+        assert_eq!(report.lines().count(), 2);
+    }
+}
+
+#[test]
+fn test_consolidated_report() {
+    let dir = tempfile::tempdir().unwrap();
+    let report = dir.path().join("consolidated.csv");
+    let rates = test_file("fx_rates.csv");
+    let input = fixture_file("example_5_multi_currency");
+
+    let output = run(&[
+        "--fx-rates",
+        rates.to_str().unwrap(),
+        "--consolidated",
+        report.to_str().unwrap(),
+        input.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert_eq!(
+        fs::read_to_string(&report).unwrap(),
+        expected("example_5_multi_currency_consolidated.csv")
+    );
+
+    let output = run(&[
+        "--fx-rates",
+        rates.to_str().unwrap(),
+        "--consolidated",
+        report.to_str().unwrap(),
+        "--report-currency",
+        "GBP",
+        input.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(3), "missing USD/GBP rate");
+}
+
+#[test]
+fn test_fee_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let fee_log = dir.path().join("fees.csv");
+    let fees = test_file("fees.toml");
+    let input = fixture_file("example_7_transfers");
+
+    let output = run(&[
+        "--fees",
+        fees.to_str().unwrap(),
+        "--fee-log",
+        fee_log.to_str().unwrap(),
+        input.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert_eq!(
+        fs::read_to_string(&fee_log).unwrap(),
+        expected("example_7_transfers_fee_log.csv")
+    );
+    let accounts = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        accounts.contains("\n1,EUR,33.245,0.0000,33.245,false,0.255,0.0000\n"),
+        "accounts: {}",
+        accounts
+    );
+
+    let output = run(&["--fees", input.to_str().unwrap(), input.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(3), "invalid fee config");
+}
+
+#[test]
+fn test_credit_limits() {
+    let limits = test_file("credit_limits.csv");
+    let input = fixture_file("example_1");
+
+    let output = run(&["--credit-limits", limits.to_str().unwrap(), input.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected("example_1_credit_limits.csv"));
+
+    let output = run(&["--credit-limits", input.to_str().unwrap(), input.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(3), "invalid limit table");
+}
+
+#[test]
+fn test_failed_report_write_exits_with_4() {
+    let dir = tempfile::tempdir().unwrap();
+    let report = dir.path().join("missing_dir").join("rejections.csv");
+    let input = fixture_file("example_1");
+
+    let output = run(&["-r", report.to_str().unwrap(), input.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(4));
+}
@@ -0,0 +1,30 @@
+//! Fixture list and helpers shared by the tests comparing outputs with the checked-in files in `test/expected/`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const FIXTURES: [&str; 7] = [
+    "example_1",
+    "example_2_all_types",
+    "example_3_large",
+    "example_4_large_many_client",
+    "example_5_multi_currency",
+    "example_6_refunds",
+    "example_7_transfers",
+];
+
+pub fn test_file(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("test")
+        .join(name)
+}
+
+/// Input CSV of the fixture.
+pub fn fixture_file(fixture: &str) -> PathBuf {
+    test_file(&format!("{}.csv", fixture))
+}
+
+/// Checked-in expected output with the given file name.
+pub fn expected(name: &str) -> String {
+    fs::read_to_string(test_file("expected").join(name)).expect("reading expected output failed")
+}
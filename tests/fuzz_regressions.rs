@@ -0,0 +1,47 @@
+//! Minimized crashing inputs found by the fuzz targets in `fuzz/`, stored in `test/fuzz_regressions/`.
+//! Every input goes through the same pipeline as the `ingest_process` target and must not panic.
+
+use payment_engine::csv_handler;
+use payment_engine::PaymentEngine;
+
+use std::fs;
+use std::path::Path;
+
+fn ingest_and_process(data: &[u8]) {
+    let mut bytes = data;
+    let rows = match csv_handler::read_transactions(&mut bytes, false) {
+        Ok(rows) => rows,
+        Err(_) => return,
+    };
+
+    let mut batch = PaymentEngine::default();
+    let batch_rejections = batch.apply_batch(rows);
+
+    let mut bytes = data;
+    let mut stream = PaymentEngine::default();
+    let stream_rejections = stream
+        .apply_stream(csv_handler::stream_transactions(&mut bytes, false))
+        .expect("input was parsed once already");
+
+    assert_eq!(batch.snapshot(), stream.snapshot());
+    assert_eq!(batch_rejections, stream_rejections);
+
+    let mut out = Vec::new();
+    csv_handler::write_accounts(&batch.snapshot(), &mut out).expect("writing to memory failed");
+}
+
+#[test]
+fn test_regressions() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("test")
+        .join("fuzz_regressions");
+    let mut count = 0;
+    for entry in fs::read_dir(dir).expect("missing regression directory") {
+        let path = entry.unwrap().path();
+        let data = fs::read(&path).unwrap();
+        let res = std::panic::catch_unwind(|| ingest_and_process(&data));
+        assert!(res.is_ok(), "{} panicked", path.display());
+        count += 1;
+    }
+    assert!(count > 0, "no regression inputs found");
+}
@@ -0,0 +1,69 @@
+//! Golden-output tests: the account CSV of every fixture in `test/` must match the checked-in
+//! expected output in `test/expected/` byte for byte, in both batch and stream mode.
+
+mod common;
+
+use common::{expected, fixture_file, FIXTURES};
+use payment_engine::account::OutputOrder;
+use payment_engine::csv_handler;
+use payment_engine::PaymentEngine;
+
+use std::fs;
+
+fn run(fixture: &str, order: OutputOrder, stream: bool) -> String {
+    let input = fs::read(fixture_file(fixture)).expect("reading fixture failed");
+    let mut bytes = input.as_slice();
+
+    let mut engine = PaymentEngine::default();
+    if stream {
+        engine
+            .apply_stream(csv_handler::stream_transactions(&mut bytes, false))
+            .expect("fixture parsing failed");
+    } else {
+        let rows =
+            csv_handler::read_transactions(&mut bytes, false).expect("fixture parsing failed");
+        engine.apply_batch(rows);
+    }
+
+    let mut out = Vec::new();
+    csv_handler::write_accounts(&engine.snapshot_ordered(order), &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn test_fixtures_match_golden_output() {
+    for fixture in FIXTURES.iter() {
+        let golden = expected(&format!("{}.csv", fixture));
+        assert_eq!(
+            run(fixture, OutputOrder::Client, false),
+            golden,
+            "batch: {}",
+            fixture
+        );
+        assert_eq!(
+            run(fixture, OutputOrder::Client, true),
+            golden,
+            "stream: {}",
+            fixture
+        );
+    }
+}
+
+#[test]
+fn test_locked_first_golden_output() {
+    assert_eq!(
+        run("example_2_all_types", OutputOrder::LockedFirst, false),
+        expected("example_2_all_types_locked_first.csv")
+    );
+}
+
+#[test]
+fn test_output_is_stable_between_runs() {
+    let first = run("example_4_large_many_client", OutputOrder::TotalDesc, false);
+    for _ in 0..5 {
+        assert_eq!(
+            run("example_4_large_many_client", OutputOrder::TotalDesc, false),
+            first
+        );
+    }
+}